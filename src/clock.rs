@@ -0,0 +1,71 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+/// Source of timestamps in nanoseconds since the unix epoch.
+pub trait Clock {
+    fn now(&self) -> u64;
+}
+
+/// Uses the IC system time inside a canister and the system clock everywhere else.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    #[cfg(target_arch = "wasm32")]
+    fn now(&self) -> u64 {
+        ic_cdk::api::time()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn now(&self) -> u64 {
+        use std::time::{SystemTime, UNIX_EPOCH};
+
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_nanos() as u64)
+            .unwrap_or_default()
+    }
+}
+
+/// A clock that only moves when told to. Clones share the same time.
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock {
+    now: Rc<Cell<u64>>,
+}
+
+impl ManualClock {
+    pub fn new(now: u64) -> Self {
+        ManualClock { now: Rc::new(Cell::new(now)) }
+    }
+
+    pub fn set(&self, now: u64) {
+        self.now.set(now);
+    }
+
+    pub fn advance(&self, by: u64) {
+        self.now.set(self.now.get() + by);
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> u64 {
+        self.now.get()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::clock::{Clock, ManualClock};
+
+    #[test]
+    pub fn it_shares_time_between_clones() {
+        let clock = ManualClock::new(10);
+        let other = clock.clone();
+
+        clock.advance(5);
+        assert_eq!(other.now(), 15);
+
+        other.set(3);
+        assert_eq!(clock.now(), 3);
+    }
+}
@@ -0,0 +1,544 @@
+use std::any::{type_name, Any, TypeId};
+use std::collections::HashMap;
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+
+use crate::clock::Clock;
+use crate::id_generator::{IdGenerator, IdGeneratorState};
+use crate::instructions::InstructionCounter;
+use crate::logging::Logger;
+use crate::message_channel::OverflowPolicy;
+use crate::metrics::OrchestratorMetrics;
+use crate::observer::StateMachineObserver;
+use crate::retry::RetryPolicy;
+use crate::state::{BoxedState, DeliveryStatus, State, StateType, Transition};
+use crate::state_machine::{StableResult, StateMachine, StateMachineHandle, StateMachineId, StepResult};
+use crate::state_machine_orchestrator::{
+    BatchReport, BudgetedStepReport, DeliveryReport, MachinePage, MachineQuery, MessageRouter, OrchestratorError, SimpleMachineOrchestrator,
+    StateMachineOrchestrator,
+};
+use crate::transition_table::StateTransitions;
+
+/// Converts between the messages of one `StateType` and the unified messages of a `HeterogeneousOrchestrator`.
+pub struct MessageAdapter<Types: StateType, Unified: StateType> {
+    /// Returns None when the message is not meant for machines of this type
+    into_machine: Box<dyn Fn(Unified::In) -> Option<Types::In>>,
+    from_machine: Box<dyn Fn(Types::Out) -> Unified::Out>,
+}
+
+impl<Types: StateType, Unified: StateType> MessageAdapter<Types, Unified> {
+    pub fn new(
+        into_machine: impl Fn(Unified::In) -> Option<Types::In> + 'static,
+        from_machine: impl Fn(Types::Out) -> Unified::Out + 'static,
+    ) -> Self {
+        MessageAdapter {
+            into_machine: Box::new(into_machine),
+            from_machine: Box::new(from_machine),
+        }
+    }
+
+    fn outbound(&self, messages: Vec<Types::Out>) -> Vec<Unified::Out> {
+        messages.into_iter().map(|message| (self.from_machine)(message)).collect()
+    }
+
+    fn adapt(self: &Rc<Self>, transition: Transition<Types>) -> Transition<Unified> {
+        match transition {
+            Transition::Same => Transition::Same,
+            Transition::Next(state) => Transition::Next(Box::new(AdaptedState { state, adapter: self.clone() })),
+            Transition::Terminal => Transition::Terminal,
+        }
+    }
+}
+
+/// A state of another `StateType` running in a `HeterogeneousOrchestrator`.
+/// Unified messages are converted on delivery and outbound messages when they are emitted.
+/// Adapted states can't be snapshotted, as the unified `StateType` could not restore them.
+pub struct AdaptedState<Types: StateType, Unified: StateType> {
+    state: BoxedState<Types>,
+    adapter: Rc<MessageAdapter<Types, Unified>>,
+}
+
+impl<Types: StateType, Unified: StateType> AdaptedState<Types, Unified> {
+    /// The state as implemented for its own `StateType`
+    pub fn inner(&self) -> &dyn State<Types> {
+        &*self.state
+    }
+
+    pub fn inner_mut(&mut self) -> &mut dyn State<Types> {
+        &mut *self.state
+    }
+}
+
+impl<Types: StateType, Unified: StateType> Debug for AdaptedState<Types, Unified> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        self.state.fmt(f)
+    }
+}
+
+impl<Types: StateType, Unified: StateType> State<Unified> for AdaptedState<Types, Unified> {
+    fn name(&self) -> String {
+        self.state.name()
+    }
+
+    fn state_key(&self) -> String {
+        self.state.state_key()
+    }
+
+    fn initialize(&self) -> Vec<Unified::Out> {
+        self.adapter.outbound(self.state.initialize())
+    }
+
+    fn deliver(&mut self, message: Unified::In) -> DeliveryStatus<Unified::In, String> {
+        let Some(message) = (self.adapter.into_machine)(message) else {
+            return DeliveryStatus::Error(format!("{} does not accept the message", type_name::<Types>()));
+        };
+
+        match self.state.deliver(message) {
+            DeliveryStatus::Delivered => DeliveryStatus::Delivered,
+            DeliveryStatus::Unexpected(message) => DeliveryStatus::Error(format!("Unexpected message: {:?}", message)),
+            DeliveryStatus::Error(error) => DeliveryStatus::Error(error),
+        }
+    }
+
+    fn advance(&self) -> Result<Transition<Unified>, String> {
+        self.state.advance().map(|transition| self.adapter.adapt(transition))
+    }
+
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        self.state.retry_policy()
+    }
+
+    fn on_cancel(&self, reason: &str) -> Vec<Unified::Out> {
+        self.adapter.outbound(self.state.on_cancel(reason))
+    }
+
+    fn transition_table(&self) -> Option<Box<dyn StateTransitions<Unified>>> {
+        let table = self.state.transition_table()?;
+        Some(Box::new(AdaptedTransitions { table, adapter: self.adapter.clone() }))
+    }
+}
+
+// Transition table of an adapted state, fired against the state it wraps
+struct AdaptedTransitions<Types: StateType, Unified: StateType> {
+    table: Box<dyn StateTransitions<Types>>,
+    adapter: Rc<MessageAdapter<Types, Unified>>,
+}
+
+impl<Types: StateType, Unified: StateType> StateTransitions<Unified> for AdaptedTransitions<Types, Unified> {
+    fn state_name(&self) -> String {
+        self.table.state_name()
+    }
+
+    fn fire(&self, state: &dyn State<Unified>) -> Option<(Transition<Unified>, Vec<Unified::Out>)> {
+        let state = state.as_any().downcast_ref::<AdaptedState<Types, Unified>>()?;
+        let (transition, emitted) = self.table.fire(&*state.state)?;
+        Some((self.adapter.adapt(transition), self.adapter.outbound(emitted)))
+    }
+
+    fn edges(&self) -> Vec<(String, Option<String>)> {
+        self.table.edges()
+    }
+}
+
+/// Hosts machines of several `StateType`s behind one id space and stepping loop.
+///
+/// Each `StateType` is registered with a `MessageAdapter`, and its states run wrapped in an `AdaptedState`.
+/// Unified inbound messages are routed by id and converted for the receiving machine, and outbound messages
+/// are converted before reaching the command handler. Messages a machine's adapter does not convert are rejected
+/// with `MessageNotAccepted`, however they are sent. Everything else, e.g. pausing, metrics or deduplication,
+/// is provided by the underlying `SimpleMachineOrchestrator`.
+pub struct HeterogeneousOrchestrator<Unified: StateType> {
+    orchestrator: SimpleMachineOrchestrator<Unified>,
+    // MessageAdapter of each registered StateType
+    adapters: HashMap<TypeId, Rc<dyn Any>>,
+}
+
+impl<Unified: StateType> HeterogeneousOrchestrator<Unified> {
+    pub fn new(command_handler: Box<dyn Fn(Unified::Out)>) -> Self {
+        HeterogeneousOrchestrator {
+            orchestrator: SimpleMachineOrchestrator::new(command_handler),
+            adapters: HashMap::new(),
+        }
+    }
+
+    /// Allow machines of the `StateType` to be created. Replaces any adapter registered for it before.
+    pub fn register<Types: StateType>(&mut self, adapter: MessageAdapter<Types, Unified>) {
+        self.adapters.insert(TypeId::of::<Types>(), Rc::new(adapter));
+    }
+
+    pub fn create_machine<Types: StateType>(&mut self, state: Box<dyn State<Types>>) -> Result<(StateMachineId, StateMachineHandle<Unified::In>), OrchestratorError> {
+        let adapter = self.adapter::<Types>()?;
+        let (machine_id, handle) = self.orchestrator.create_machine(Box::new(AdaptedState { state, adapter: adapter.clone() }))?;
+        self.filter_messages(&machine_id, adapter);
+        Ok((machine_id, handle))
+    }
+
+    /// Create a machine with an id chosen by the caller, e.g. an idempotency key
+    pub fn create_machine_with_id<Types: StateType>(&mut self, machine_id: StateMachineId, state: Box<dyn State<Types>>) -> Result<(StateMachineId, StateMachineHandle<Unified::In>), OrchestratorError> {
+        let adapter = self.adapter::<Types>()?;
+        let (machine_id, handle) = self.orchestrator.create_machine_with_id(machine_id, Box::new(AdaptedState { state, adapter: adapter.clone() }))?;
+        self.filter_messages(&machine_id, adapter);
+        Ok((machine_id, handle))
+    }
+
+    /// Wrap a state of a registered `StateType`, e.g. to pass it to `force_transition`
+    pub fn adapt<Types: StateType>(&self, state: Box<dyn State<Types>>) -> Result<BoxedState<Unified>, OrchestratorError> {
+        Ok(Box::new(AdaptedState { state, adapter: self.adapter::<Types>()? }))
+    }
+
+    fn adapter<Types: StateType>(&self) -> Result<Rc<MessageAdapter<Types, Unified>>, OrchestratorError> {
+        self.adapters
+            .get(&TypeId::of::<Types>())
+            .cloned()
+            .and_then(|adapter| adapter.downcast::<MessageAdapter<Types, Unified>>().ok())
+            .ok_or_else(|| OrchestratorError::TypeNotRegistered(type_name::<Types>().to_string()))
+    }
+
+    // Reject messages the adapter of the machine does not convert
+    fn filter_messages<Types: StateType>(&mut self, machine_id: &StateMachineId, adapter: Rc<MessageAdapter<Types, Unified>>) {
+        let filter = move |message: &Unified::In| (adapter.into_machine)(message.clone()).is_some();
+        self.orchestrator.set_message_filter(machine_id.clone(), Box::new(filter));
+    }
+
+    /// The current state of the machine downcast to `T`, if the machine exists and is in a `T` of the `StateType`
+    pub fn downcast_state<Types: StateType, T: State<Types>>(&self, id: &StateMachineId) -> Option<&T> {
+        self.orchestrator.get_state_machine(id)?
+            .downcast_state::<AdaptedState<Types, Unified>>()?
+            .inner()
+            .as_any()
+            .downcast_ref::<T>()
+    }
+
+    /// Name of the current state of the machine
+    pub fn state_name(&self, id: &StateMachineId) -> Option<String> {
+        self.orchestrator.get_state_machine(id).map(|machine| machine.state().name())
+    }
+
+    pub fn get_state_machine(&self, id: &StateMachineId) -> Option<&StateMachine<Unified>> {
+        self.orchestrator.get_state_machine(id)
+    }
+
+    /// See `SimpleMachineOrchestrator::set_id_generator`
+    pub fn set_id_generator(&mut self, id_generator: Box<dyn IdGenerator>) {
+        self.orchestrator.set_id_generator(id_generator)
+    }
+
+    pub fn export_id_generator_state(&self) -> Option<IdGeneratorState> {
+        self.orchestrator.export_id_generator_state()
+    }
+
+    pub fn restore_id_generator_state(&mut self, state: &IdGeneratorState) -> Result<(), String> {
+        self.orchestrator.restore_id_generator_state(state)
+    }
+
+    /// See `SimpleMachineOrchestrator::list_machines`
+    pub fn list_machines(&self, query: &MachineQuery) -> MachinePage {
+        self.orchestrator.list_machines(query)
+    }
+
+    pub fn tag_machine(&mut self, machine_id: &StateMachineId, tag: &str) -> Result<(), OrchestratorError> {
+        self.orchestrator.tag_machine(machine_id, tag)
+    }
+
+    pub fn untag_machine(&mut self, machine_id: &StateMachineId, tag: &str) {
+        self.orchestrator.untag_machine(machine_id, tag)
+    }
+
+    pub fn machine_tags(&self, machine_id: &StateMachineId) -> Vec<String> {
+        self.orchestrator.machine_tags(machine_id)
+    }
+
+    /// Deliver the message to every machine whose adapter converts it, rejecting it for the others
+    pub fn broadcast(&mut self, message: Unified::In) -> DeliveryReport {
+        self.orchestrator.broadcast(message)
+    }
+
+    /// Deliver the message to every machine with the tag whose adapter converts it, rejecting it for the others
+    pub fn multicast_to_tag(&mut self, tag: &str, message: Unified::In) -> DeliveryReport {
+        self.orchestrator.multicast_to_tag(tag, message)
+    }
+
+    /// Deliver the message to every machine in the state whose adapter converts it, rejecting it for the others
+    pub fn multicast_to_state(&mut self, state_name: &str, message: Unified::In) -> DeliveryReport {
+        self.orchestrator.multicast_to_state(state_name, message)
+    }
+
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.orchestrator.set_clock(clock)
+    }
+
+    pub fn set_logger(&mut self, logger: Rc<dyn Logger>) {
+        self.orchestrator.set_logger(logger)
+    }
+
+    pub fn add_observer(&mut self, observer: Rc<dyn StateMachineObserver<Unified>>) {
+        self.orchestrator.add_observer(observer)
+    }
+
+    pub fn set_inbound_capacity(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.orchestrator.set_inbound_capacity(capacity, policy)
+    }
+
+    /// See `SimpleMachineOrchestrator::enable_deduplication`
+    pub fn enable_deduplication(&mut self, capacity: usize, window: u64) {
+        self.orchestrator.enable_deduplication(capacity, window)
+    }
+
+    /// Deliver unified outbound messages the router accepts to other machines.
+    /// Routed messages the receiving machine's adapter does not convert are logged and dropped.
+    pub fn set_router(&mut self, router: MessageRouter<Unified>) {
+        self.orchestrator.set_router(router)
+    }
+
+    pub fn set_max_hops(&mut self, max_hops: usize) {
+        self.orchestrator.set_max_hops(max_hops)
+    }
+
+    pub fn set_instruction_counter(&mut self, instruction_counter: Rc<dyn InstructionCounter>) {
+        self.orchestrator.set_instruction_counter(instruction_counter)
+    }
+
+    pub fn enable_metrics(&mut self) {
+        self.orchestrator.enable_metrics()
+    }
+
+    pub fn metrics(&self) -> Option<&OrchestratorMetrics> {
+        self.orchestrator.metrics()
+    }
+
+    pub fn render_prometheus_metrics(&self) -> Option<String> {
+        self.orchestrator.render_prometheus_metrics()
+    }
+
+    pub fn step_all_machines(&mut self) {
+        self.orchestrator.step_all_machines()
+    }
+
+    pub fn step_until_stable(&mut self, machine_id: &str, max_steps: u64) -> Result<StableResult, OrchestratorError> {
+        self.orchestrator.step_until_stable(machine_id, max_steps)
+    }
+
+    pub fn cancel(&mut self, machine_id: &str, reason: &str) -> Result<(), OrchestratorError> {
+        self.orchestrator.cancel(machine_id, reason)
+    }
+
+    pub fn pause(&mut self, machine_id: &str) -> Result<(), OrchestratorError> {
+        self.orchestrator.pause(machine_id)
+    }
+
+    pub fn resume(&mut self, machine_id: &str) -> Result<(), OrchestratorError> {
+        self.orchestrator.resume(machine_id)
+    }
+
+    /// Move the machine to the state, e.g. one wrapped by `adapt`. See `SimpleMachineOrchestrator::force_transition`.
+    pub fn force_transition(&mut self, machine_id: &str, state: BoxedState<Unified>, reason: &str) -> Result<(), OrchestratorError> {
+        self.orchestrator.force_transition(machine_id, state, reason)
+    }
+
+    /// Deliver the message to its machine ahead of the queue, if the machine's adapter converts it.
+    /// See `SimpleMachineOrchestrator::inject_message`.
+    pub fn inject_message(&mut self, message: Unified::In, reason: &str) -> Result<(), OrchestratorError> {
+        self.orchestrator.inject_message(message, reason)
+    }
+
+    /// Edit the machine's current state in place if it is a `T` of the `StateType`
+    pub fn edit_state<Types: StateType, T: State<Types>>(&mut self, machine_id: &str, reason: &str, edit: impl FnOnce(&mut T)) -> Result<(), OrchestratorError> {
+        let name = self.state_name(&machine_id.to_string()).ok_or_else(|| OrchestratorError::MachineNotFound(machine_id.to_string()))?;
+        if self.downcast_state::<Types, T>(&machine_id.to_string()).is_none() {
+            return Err(OrchestratorError::StateMismatch(machine_id.to_string(), name));
+        }
+
+        self.orchestrator.edit_state::<AdaptedState<Types, Unified>>(machine_id, reason, |state| {
+            if let Some(state) = state.inner_mut().as_any_mut().downcast_mut::<T>() {
+                edit(state);
+            }
+        })
+    }
+
+    pub fn wake(&mut self, machine_id: &StateMachineId) -> Result<(), OrchestratorError> {
+        self.orchestrator.wake(machine_id)
+    }
+
+    pub fn wake_at(&mut self, machine_id: &StateMachineId, at: u64) -> Result<(), OrchestratorError> {
+        self.orchestrator.wake_at(machine_id, at)
+    }
+
+    pub fn ready_machines(&mut self) -> Vec<StateMachineId> {
+        self.orchestrator.ready_machines()
+    }
+
+    pub fn step_ready_machines(&mut self) -> Vec<(StateMachineId, Result<StepResult, OrchestratorError>)> {
+        self.orchestrator.step_ready_machines()
+    }
+
+    /// See `SimpleMachineOrchestrator::step_machines_within`
+    pub fn step_machines_within(&mut self, instruction_limit: u64) -> BudgetedStepReport {
+        self.orchestrator.step_machines_within(instruction_limit)
+    }
+}
+
+impl<Unified: StateType> StateMachineOrchestrator<Unified> for HeterogeneousOrchestrator<Unified> {
+    /// Create a machine whose states are already of the unified `StateType`
+    fn create_machine(&mut self, state: Box<dyn State<Unified>>) -> Result<(StateMachineId, StateMachineHandle<Unified::In>), OrchestratorError> {
+        self.orchestrator.create_machine(state)
+    }
+
+    /// Pass the message to the machine it is addressed to, converted to that machine's type, and step it
+    fn handle_message(&mut self, message: Unified::In) -> Result<StepResult, OrchestratorError> {
+        self.orchestrator.handle_message(message)
+    }
+
+    fn handle_messages(&mut self, messages: Vec<Unified::In>) -> BatchReport<Unified::In> {
+        self.orchestrator.handle_messages(messages)
+    }
+
+    fn step_machine(&mut self, machine_id: &str) -> Result<StepResult, OrchestratorError> {
+        self.orchestrator.step_machine(machine_id)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::heterogeneous_orchestrator::{HeterogeneousOrchestrator, MessageAdapter};
+    use crate::state::{StateMachineMessage, StateType};
+    use crate::state_machine::{MachineStatus, StepResult};
+    use crate::state_machine_orchestrator::{OrchestratorError, StateMachineOrchestrator};
+    use crate::tests::example_2_simple_inbound_messages::{self as counters, BlueMessageState, RedMessageState, SimpleMessage};
+    use crate::tests::example_6_machine_routing::{self as relays, Hop, Relay, RelayCommand};
+
+    #[derive(Clone, Debug)]
+    enum Inbound {
+        Counter(SimpleMessage),
+        Relay(Hop),
+    }
+
+    impl StateMachineMessage for Inbound {
+        fn id(&self) -> &String {
+            match self {
+                Inbound::Counter(message) => message.id(),
+                Inbound::Relay(message) => message.id(),
+            }
+        }
+
+        fn unpack(self) -> Self {
+            self
+        }
+    }
+
+    struct Unified {}
+
+    impl StateType for Unified {
+        type In = Inbound;
+        type Out = RelayCommand;
+    }
+
+    fn orchestrator(commands: Rc<RefCell<Vec<RelayCommand>>>) -> HeterogeneousOrchestrator<Unified> {
+        let mut orchestrator = HeterogeneousOrchestrator::new(Box::new(move |command| commands.borrow_mut().push(command)));
+        orchestrator.register::<counters::MachineTypes>(MessageAdapter::new(
+            |message| match message {
+                Inbound::Counter(message) => Some(message),
+                _ => None,
+            },
+            |_| unreachable!("counters emit no messages"),
+        ));
+        orchestrator.register::<relays::MachineTypes>(MessageAdapter::new(
+            |message| match message {
+                Inbound::Relay(hop) => Some(hop),
+                _ => None,
+            },
+            |command| command,
+        ));
+        orchestrator
+    }
+
+    fn increment(machine_id: &str) -> Inbound {
+        Inbound::Counter(SimpleMessage::IncrementRed { machine_id: machine_id.to_string() })
+    }
+
+    #[test]
+    pub fn it_hosts_machines_of_different_types() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let mut orchestrator = orchestrator(commands.clone());
+
+        let (counter, _) = orchestrator.create_machine(Box::new(RedMessageState::new())).unwrap();
+        let (relay, _) = orchestrator.create_machine(Box::new(Relay { next: None, id: "relay".to_string() })).unwrap();
+        assert_ne!(counter, relay);
+
+        for _ in 0..3 {
+            orchestrator.handle_message(increment(&counter)).unwrap();
+        }
+        assert_eq!(orchestrator.handle_message(Inbound::Relay(Hop { machine_id: relay.clone() })), Ok(StepResult::Running));
+
+        assert_eq!(orchestrator.state_name(&counter), Some("BlueMessageState".to_string()));
+        assert!(orchestrator.downcast_state::<counters::MachineTypes, BlueMessageState>(&counter).is_some());
+        assert!(orchestrator.downcast_state::<relays::MachineTypes, Relay>(&counter).is_none());
+        assert_eq!(*commands.borrow(), vec![RelayCommand::Report { id: "relay".to_string() }]);
+    }
+
+    #[test]
+    pub fn it_rejects_messages_and_types_it_cannot_adapt() {
+        let mut orchestrator = orchestrator(Rc::new(RefCell::new(vec![])));
+        let (relay, _) = orchestrator.create_machine(Box::new(Relay { next: None, id: "relay".to_string() })).unwrap();
+
+        let result = orchestrator.handle_message(increment(&relay));
+        assert_eq!(result, Err(OrchestratorError::MessageNotAccepted(relay.clone())));
+        let report = orchestrator.handle_messages(vec![increment(&relay)]);
+        assert_eq!(report.results, vec![(relay.clone(), Err(OrchestratorError::MessageNotAccepted(relay)))]);
+
+        let mut empty = HeterogeneousOrchestrator::<Unified>::new(Box::new(|_| {}));
+        assert!(matches!(empty.create_machine(Box::new(RedMessageState::new())), Err(OrchestratorError::TypeNotRegistered(_))));
+    }
+
+    #[test]
+    pub fn it_manages_adapted_machines_like_any_other() {
+        let mut orchestrator = orchestrator(Rc::new(RefCell::new(vec![])));
+        orchestrator.enable_metrics();
+
+        let (counter, _) = orchestrator.create_machine_with_id("counter".to_string(), Box::new(RedMessageState::new())).unwrap();
+        let duplicate = orchestrator.create_machine_with_id("counter".to_string(), Box::new(RedMessageState::new()));
+        assert!(matches!(duplicate, Err(OrchestratorError::DuplicateId(_))));
+
+        orchestrator.pause(&counter).unwrap();
+        assert_eq!(orchestrator.handle_message(increment(&counter)), Ok(StepResult::Paused));
+        orchestrator.resume(&counter).unwrap();
+        orchestrator.step_ready_machines();
+        assert_eq!(orchestrator.downcast_state::<counters::MachineTypes, RedMessageState>(&counter), Some(&RedMessageState { count: 1 }));
+
+        let blue = orchestrator.adapt::<counters::MachineTypes>(Box::new(BlueMessageState { count: 0 })).unwrap();
+        orchestrator.force_transition(&counter, blue, "skip red").unwrap();
+        assert_eq!(orchestrator.state_name(&counter), Some("BlueMessageState".to_string()));
+
+        orchestrator.cancel(&counter, "no longer needed").unwrap();
+        assert_eq!(orchestrator.get_state_machine(&counter).unwrap().status(), &MachineStatus::Cancelled { reason: "no longer needed".to_string() });
+        assert!(orchestrator.metrics().is_some());
+    }
+
+    #[test]
+    pub fn it_applies_adapters_however_messages_are_sent() {
+        let mut orchestrator = orchestrator(Rc::new(RefCell::new(vec![])));
+        orchestrator.set_router(Box::new(|command| match command {
+            RelayCommand::Forward(hop) => Ok(Inbound::Relay(hop)),
+            command => Err(command),
+        }));
+        let (counter, _) = orchestrator.create_machine(Box::new(RedMessageState::new())).unwrap();
+        let (relay, _) = orchestrator.create_machine(Box::new(Relay { next: Some(counter.clone()), id: "relay".to_string() })).unwrap();
+
+        let report = orchestrator.broadcast(increment(&counter));
+        assert_eq!(report.accepted, vec![counter.clone()]);
+        assert_eq!(report.rejected, vec![(relay.clone(), OrchestratorError::MessageNotAccepted(relay.clone()))]);
+        assert_eq!(orchestrator.inject_message(increment(&relay), "repair"), Err(OrchestratorError::MessageNotAccepted(relay.clone())));
+
+        // The relay forwards a hop to the counter when it initializes, which the counter's adapter does not convert
+        orchestrator.step_machine(&relay).unwrap();
+        assert_eq!(orchestrator.get_state_machine(&counter).unwrap().status(), &MachineStatus::Running);
+
+        orchestrator.edit_state::<counters::MachineTypes, RedMessageState>(&counter, "recount", |state| state.count = 2).unwrap();
+        assert_eq!(orchestrator.downcast_state::<counters::MachineTypes, RedMessageState>(&counter), Some(&RedMessageState { count: 2 }));
+        let result = orchestrator.edit_state::<counters::MachineTypes, BlueMessageState>(&counter, "recount", |state| state.count = 2);
+        assert_eq!(result, Err(OrchestratorError::StateMismatch(counter.clone(), "RedMessageState".to_string())));
+        assert!(orchestrator.get_state_machine(&counter).unwrap().state().snapshot().is_none());
+    }
+}
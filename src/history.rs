@@ -0,0 +1,200 @@
+use std::collections::VecDeque;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+/// A single transition taken by a state machine.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct TransitionRecord<In, Out> {
+    /// The step of the machine in which the transition happened, starting at 1
+    pub step: u64,
+    /// Time of the transition in nanoseconds since the unix epoch
+    pub timestamp: u64,
+    /// Name of the state that was left
+    pub from: String,
+    /// Name of the state that was entered. None when the machine terminated or was cancelled.
+    pub to: Option<String>,
+    /// Messages delivered to the `from` state while it was current
+    pub messages: Vec<In>,
+    /// Messages the `from` state emitted while it was current
+    pub emitted: Vec<Out>,
+    /// Set when an administrator made the record instead of a step
+    pub admin_action: Option<AdminAction>,
+    /// Set when the machine was cancelled, to the reason it was cancelled for
+    pub cancel_reason: Option<String>,
+}
+
+/// A repair made by an administrator, with the reason they gave
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum AdminAction {
+    /// The machine was moved to the `to` state without `advance`
+    ForceTransition { reason: String },
+    /// The message was delivered ahead of the queue
+    InjectMessage { reason: String },
+    /// The current state was edited in place
+    EditState { reason: String },
+}
+
+/// Bounded log of the most recent transitions of a state machine.
+/// Once full, the oldest record is dropped for every new one.
+#[derive(Debug, Clone)]
+pub struct TransitionHistory<In, Out> {
+    capacity: usize,
+    records: VecDeque<TransitionRecord<In, Out>>,
+}
+
+impl<In: Clone, Out: Clone> TransitionHistory<In, Out> {
+    pub fn new(capacity: usize) -> Self {
+        TransitionHistory {
+            capacity,
+            records: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    pub fn record(&mut self, record: TransitionRecord<In, Out>) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        if self.records.len() == self.capacity {
+            self.records.pop_front();
+        }
+        self.records.push_back(record);
+    }
+
+    /// Records from oldest to newest
+    pub fn records(&self) -> impl Iterator<Item=&TransitionRecord<In, Out>> {
+        self.records.iter()
+    }
+
+    pub fn last(&self) -> Option<&TransitionRecord<In, Out>> {
+        self.records.back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.records.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Copy the records out, oldest first, e.g. to return them from a query endpoint.
+    pub fn export(&self) -> Vec<TransitionRecord<In, Out>> {
+        self.records.iter().cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use candid::{decode_one, encode_one};
+
+    use crate::clock::ManualClock;
+    use crate::history::{TransitionHistory, TransitionRecord};
+    use crate::message_channel::create_channel;
+    use crate::state::NoMessage;
+    use crate::state_machine::StateMachine;
+    use crate::tests::example_1_simple::Red;
+    use crate::tests::example_2_simple_inbound_messages::{RedMessageState, SimpleMessage};
+
+    fn record(step: u64) -> TransitionRecord<u64, u64> {
+        TransitionRecord {
+            step,
+            timestamp: step * 10,
+            from: "A".to_string(),
+            to: Some("B".to_string()),
+            messages: vec![step],
+            emitted: vec![],
+            admin_action: None,
+            cancel_reason: None,
+        }
+    }
+
+    #[test]
+    pub fn it_drops_the_oldest_record_when_full() {
+        let mut history = TransitionHistory::new(2);
+
+        history.record(record(1));
+        history.record(record(2));
+        history.record(record(3));
+
+        assert_eq!(history.len(), 2);
+        assert_eq!(history.records().map(|r| r.step).collect::<Vec<_>>(), vec![2, 3]);
+    }
+
+    #[test]
+    pub fn it_records_machine_transitions() {
+        let clock = ManualClock::new(100);
+        let (sender, _) = create_channel();
+        let (mut machine, _) = StateMachine::new("simple".to_string(), sender, Box::new(Red::new()));
+        machine.set_clock(Rc::new(clock.clone()));
+        machine.enable_history(10);
+
+        machine.step().unwrap();
+        clock.advance(5);
+        machine.step().unwrap();
+
+        let records = machine.history().unwrap().export();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0].step, 1);
+        assert_eq!(records[0].timestamp, 100);
+        assert_eq!(records[0].from, "Red");
+        assert_eq!(records[0].to, Some("Blue".to_string()));
+        assert_eq!(records[1].step, 2);
+        assert_eq!(records[1].timestamp, 105);
+        assert_eq!(records[1].from, "Blue");
+    }
+
+    #[test]
+    pub fn it_records_delivered_messages() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("simple".to_string(), sender, Box::new(RedMessageState::new()));
+        machine.enable_history(10);
+
+        for id in ["one", "two", "three"] {
+            handle.send(SimpleMessage::IncrementRed { machine_id: id.to_string() }).unwrap();
+            machine.step().unwrap();
+        }
+
+        let history = machine.history().unwrap();
+        assert_eq!(history.len(), 1);
+
+        let record = history.last().unwrap();
+        assert_eq!(record.step, 3);
+        assert_eq!(record.from, "RedMessageState");
+        assert_eq!(record.to, Some("BlueMessageState".to_string()));
+        assert_eq!(record.messages.len(), 3);
+    }
+
+    #[test]
+    pub fn it_tells_cancellations_from_terminations() {
+        let (sender, _) = create_channel();
+        let (mut machine, _) = StateMachine::new("simple".to_string(), sender, Box::new(Red::new()));
+        machine.enable_history(10);
+
+        machine.cancel("operator request").unwrap();
+
+        let record = machine.history().unwrap().last().unwrap();
+        assert_eq!(record.to, None);
+        assert_eq!(record.cancel_reason, Some("operator request".to_string()));
+    }
+
+    #[test]
+    pub fn it_exports_as_candid() {
+        let (sender, _) = create_channel();
+        let (mut machine, _) = StateMachine::new("simple".to_string(), sender, Box::new(Red::new()));
+        machine.enable_history(10);
+        machine.step().unwrap();
+
+        let records = machine.history().unwrap().export();
+        let bytes = encode_one(&records).unwrap();
+        let decoded: Vec<TransitionRecord<NoMessage, NoMessage>> = decode_one(&bytes).unwrap();
+        assert_eq!(decoded, records);
+    }
+}
@@ -0,0 +1,211 @@
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::random::SeededRng;
+use crate::state_machine::StateMachineId;
+
+/// Produces ids for machines created without one.
+/// Orchestrators skip generated ids that are already in use.
+pub trait IdGenerator {
+    fn generate(&mut self) -> StateMachineId;
+
+    /// Where the generator is at, to persist across an upgrade. None when it has nothing to export.
+    fn export_state(&self) -> Option<IdGeneratorState> {
+        None
+    }
+
+    /// Continue from an exported state
+    fn restore_state(&mut self, _state: &IdGeneratorState) -> Result<(), String> {
+        Err("The id generator can't restore its state".to_string())
+    }
+}
+
+/// Exported state of an id generator
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum IdGeneratorState {
+    /// The value the next id of a `MonotonicIdGenerator` is generated from
+    Counter(u64),
+    /// The random number generator state of a `UuidV4Generator`, see `SeededRng::state`
+    Random(Vec<u64>),
+}
+
+/// Counts up from a starting value: "0", "1", "2", ...
+#[derive(Debug, Clone, Default)]
+pub struct MonotonicIdGenerator {
+    next: u64,
+}
+
+impl MonotonicIdGenerator {
+    pub fn new() -> Self {
+        Self::starting_at(0)
+    }
+
+    /// Continue from a counter persisted before an upgrade
+    pub fn starting_at(next: u64) -> Self {
+        MonotonicIdGenerator { next }
+    }
+
+    /// The value the next id is generated from
+    pub fn next(&self) -> u64 {
+        self.next
+    }
+}
+
+impl IdGenerator for MonotonicIdGenerator {
+    fn generate(&mut self) -> StateMachineId {
+        let id = self.next.to_string();
+        self.next += 1;
+        id
+    }
+
+    fn export_state(&self) -> Option<IdGeneratorState> {
+        Some(IdGeneratorState::Counter(self.next))
+    }
+
+    fn restore_state(&mut self, state: &IdGeneratorState) -> Result<(), String> {
+        match state {
+            IdGeneratorState::Counter(next) => {
+                self.next = *next;
+                Ok(())
+            }
+            _ => Err(format!("Not a counter: {:?}", state)),
+        }
+    }
+}
+
+/// Random version 4 UUIDs, which don't reveal the order machines were created in
+#[derive(Debug, Clone)]
+pub struct UuidV4Generator {
+    rng: SeededRng,
+}
+
+impl UuidV4Generator {
+    pub fn new(rng: SeededRng) -> Self {
+        UuidV4Generator { rng }
+    }
+
+    /// Seed from the management canister's `raw_rand`
+    #[cfg(target_arch = "wasm32")]
+    pub async fn from_raw_rand() -> Result<Self, String> {
+        Ok(UuidV4Generator::new(SeededRng::from_raw_rand().await?))
+    }
+}
+
+impl IdGenerator for UuidV4Generator {
+    fn generate(&mut self) -> StateMachineId {
+        let mut bytes = [0u8; 16];
+        self.rng.fill_bytes(&mut bytes);
+        bytes[6] = (bytes[6] & 0x0F) | 0x40;
+        bytes[8] = (bytes[8] & 0x3F) | 0x80;
+
+        let hex: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+        format!("{}-{}-{}-{}-{}", &hex[0..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..32])
+    }
+
+    fn export_state(&self) -> Option<IdGeneratorState> {
+        Some(IdGeneratorState::Random(self.rng.state()))
+    }
+
+    fn restore_state(&mut self, state: &IdGeneratorState) -> Result<(), String> {
+        match state {
+            IdGeneratorState::Random(words) => {
+                self.rng = SeededRng::from_state(words).ok_or_else(|| format!("Invalid random state: {:?}", words))?;
+                Ok(())
+            }
+            _ => Err(format!("Not a random state: {:?}", state)),
+        }
+    }
+}
+
+/// Prefixes the ids of another generator with the principal of the caller, e.g. "aaaaa-aa/0"
+pub struct PrincipalScopedIdGenerator {
+    inner: Box<dyn IdGenerator>,
+    caller: Box<dyn Fn() -> String>,
+}
+
+impl PrincipalScopedIdGenerator {
+    /// Scope ids to `ic_cdk::api::caller`. Outside a canister the anonymous principal is used.
+    pub fn new(inner: Box<dyn IdGenerator>) -> Self {
+        Self::with_caller(inner, Box::new(caller))
+    }
+
+    /// Scope ids to the principal returned by `caller`
+    pub fn with_caller(inner: Box<dyn IdGenerator>, caller: Box<dyn Fn() -> String>) -> Self {
+        PrincipalScopedIdGenerator { inner, caller }
+    }
+}
+
+impl IdGenerator for PrincipalScopedIdGenerator {
+    fn generate(&mut self) -> StateMachineId {
+        format!("{}/{}", (self.caller)(), self.inner.generate())
+    }
+
+    fn export_state(&self) -> Option<IdGeneratorState> {
+        self.inner.export_state()
+    }
+
+    fn restore_state(&mut self, state: &IdGeneratorState) -> Result<(), String> {
+        self.inner.restore_state(state)
+    }
+}
+
+#[cfg(target_arch = "wasm32")]
+fn caller() -> String {
+    ic_cdk::api::caller().to_text()
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn caller() -> String {
+    candid::Principal::anonymous().to_text()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::id_generator::{IdGenerator, IdGeneratorState, MonotonicIdGenerator, PrincipalScopedIdGenerator, UuidV4Generator};
+    use crate::random::SeededRng;
+
+    #[test]
+    pub fn it_generates_monotonic_ids() {
+        let mut generator = MonotonicIdGenerator::starting_at(41);
+
+        assert_eq!(generator.generate(), "41");
+        assert_eq!(generator.generate(), "42");
+        assert_eq!(generator.next(), 43);
+    }
+
+    #[test]
+    pub fn it_generates_uuids() {
+        let mut generator = UuidV4Generator::new(SeededRng::new(1));
+
+        let one = generator.generate();
+        let two = generator.generate();
+        assert_ne!(one, two);
+        assert_eq!(one.len(), 36);
+        assert_eq!(&one[14..15], "4");
+        assert!(["8", "9", "a", "b"].contains(&&one[19..20]));
+    }
+
+    #[test]
+    pub fn it_restores_exported_states() {
+        let mut uuids = UuidV4Generator::new(SeededRng::new(1));
+        let state = uuids.export_state().unwrap();
+        let expected = uuids.generate();
+
+        let mut restored = UuidV4Generator::new(SeededRng::new(2));
+        restored.restore_state(&state).unwrap();
+        assert_eq!(restored.generate(), expected);
+
+        let mut counter = MonotonicIdGenerator::new();
+        counter.restore_state(&IdGeneratorState::Counter(9)).unwrap();
+        assert_eq!(counter.generate(), "9");
+        assert!(counter.restore_state(&state).is_err());
+    }
+
+    #[test]
+    pub fn it_scopes_ids_to_the_caller() {
+        let mut generator = PrincipalScopedIdGenerator::with_caller(Box::new(MonotonicIdGenerator::new()), Box::new(|| "aaaaa-aa".to_string()));
+
+        assert_eq!(generator.generate(), "aaaaa-aa/0");
+        assert_eq!(PrincipalScopedIdGenerator::new(Box::new(MonotonicIdGenerator::new())).generate(), "2vxsx-fae/0");
+    }
+}
@@ -0,0 +1,127 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, VecDeque};
+use std::hash::Hash;
+
+/// Remembers the outcome of recently handled messages by idempotency key,
+/// so a retried message can be answered without handling it again.
+///
+/// Holds at most `capacity` keys, each for at most `window` nanoseconds.
+#[derive(Debug)]
+pub struct IdempotencyCache<K, T> {
+    capacity: usize,
+    window: u64,
+    // Outcome of each key, with the sequence number of its last insert
+    outcomes: HashMap<K, (u64, T)>,
+    // Keys in the order they were inserted, with the time and sequence number of the insert.
+    // Entries of keys inserted again since are skipped when they reach the front.
+    order: VecDeque<(K, u64, u64)>,
+    sequence: u64,
+}
+
+impl<K: Clone + Eq + Hash, T: Clone> IdempotencyCache<K, T> {
+    pub fn new(capacity: usize, window: u64) -> Self {
+        IdempotencyCache {
+            capacity,
+            window,
+            outcomes: HashMap::new(),
+            order: VecDeque::new(),
+            sequence: 0,
+        }
+    }
+
+    /// Outcome recorded for the key, unless it expired by `now`
+    pub fn get<Q>(&mut self, key: &Q, now: u64) -> Option<T>
+        where K: Borrow<Q>, Q: Eq + Hash + ?Sized
+    {
+        self.expire(now);
+        self.outcomes.get(key).map(|(_, outcome)| outcome.clone())
+    }
+
+    pub fn insert(&mut self, key: K, now: u64, outcome: T) {
+        if self.capacity == 0 {
+            return;
+        }
+
+        self.expire(now);
+        if self.outcomes.len() == self.capacity && !self.outcomes.contains_key(&key) {
+            while let Some((oldest, _, sequence)) = self.order.pop_front() {
+                if self.is_current(&oldest, sequence) {
+                    self.outcomes.remove(&oldest);
+                    break;
+                }
+            }
+        }
+
+        self.sequence += 1;
+        self.order.push_back((key.clone(), now, self.sequence));
+        self.outcomes.insert(key, (self.sequence, outcome));
+    }
+
+    pub fn len(&self) -> usize {
+        self.outcomes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.outcomes.is_empty()
+    }
+
+    fn expire(&mut self, now: u64) {
+        while let Some((key, inserted_at, sequence)) = self.order.front() {
+            if now.saturating_sub(*inserted_at) < self.window {
+                break;
+            }
+            if self.is_current(key, *sequence) {
+                self.outcomes.remove(key);
+            }
+            self.order.pop_front();
+        }
+    }
+
+    // Whether the entry of `order` is the last insert of the key
+    fn is_current(&self, key: &K, sequence: u64) -> bool {
+        self.outcomes.get(key).is_some_and(|(current, _)| *current == sequence)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::idempotency::IdempotencyCache;
+
+    #[test]
+    pub fn it_forgets_outcomes_after_the_window() {
+        let mut cache = IdempotencyCache::new(10, 100);
+        cache.insert("a".to_string(), 0, 1);
+        cache.insert("b".to_string(), 50, 2);
+
+        assert_eq!(cache.get("a", 99), Some(1));
+        assert_eq!(cache.get("a", 100), None);
+        assert_eq!(cache.get("b", 100), Some(2));
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    pub fn it_evicts_the_oldest_key_when_full() {
+        let mut cache = IdempotencyCache::new(2, 100);
+        cache.insert("a".to_string(), 0, 1);
+        cache.insert("b".to_string(), 1, 2);
+        cache.insert("c".to_string(), 2, 3);
+
+        assert_eq!(cache.get("a", 3), None);
+        assert_eq!(cache.get("b", 3), Some(2));
+        assert_eq!(cache.get("c", 3), Some(3));
+    }
+
+    #[test]
+    pub fn it_keeps_keys_inserted_again() {
+        let mut cache = IdempotencyCache::new(2, 100);
+        cache.insert("a".to_string(), 0, 1);
+        cache.insert("b".to_string(), 1, 2);
+        cache.insert("a".to_string(), 2, 3);
+        cache.insert("c".to_string(), 3, 4);
+
+        assert_eq!(cache.get("a", 4), Some(3));
+        assert_eq!(cache.get("b", 4), None);
+        assert_eq!(cache.get("a", 101), Some(3));
+        assert_eq!(cache.get("a", 102), None);
+    }
+}
@@ -0,0 +1,20 @@
+/// Source of the number of instructions executed in the current message.
+pub trait InstructionCounter {
+    fn instructions(&self) -> u64;
+}
+
+/// Reads the IC instruction counter inside a canister. Always 0 everywhere else.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct IcInstructionCounter;
+
+impl InstructionCounter for IcInstructionCounter {
+    #[cfg(target_arch = "wasm32")]
+    fn instructions(&self) -> u64 {
+        ic_cdk::api::instruction_counter()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    fn instructions(&self) -> u64 {
+        0
+    }
+}
@@ -0,0 +1,330 @@
+use candid::CandidType;
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+
+use crate::state::StateType;
+use crate::state_machine::MachineStatus;
+
+/// A step of a state machine that changed its state.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct JournalEntry<In> {
+    /// The step of the machine, starting at 1
+    pub step: u64,
+    /// Messages delivered during the step, in delivery order
+    pub messages: Vec<In>,
+    /// False when the step failed delivering a message and never reached `advance`
+    pub advanced: bool,
+    /// Set when the entry records a status change, e.g. pausing the machine, rather than a step
+    pub status: Option<MachineStatus>,
+    /// Retry state the step left, set when the step failed to advance or followed a failed attempt
+    pub retry: Option<RetryState>,
+    /// Set when the last message was answered with `DeliveryStatus::Error`, to the error. The state consumed it,
+    /// so replays deliver it as well and expect the same error.
+    pub delivery_error: Option<String>,
+}
+
+/// Failed attempts to advance the current state, and the random number generator drawing their jitter
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct RetryState {
+    pub attempts: u32,
+    pub next_retry_at: Option<u64>,
+    /// See `SeededRng::state`
+    pub rng: Vec<u64>,
+}
+
+/// Ordered log of the inbound messages delivered to a machine.
+///
+/// Replaying the entries against the initial state, or the snapshot when the journal was
+/// compacted, rebuilds the current state of the machine. Steps that delivered nothing, did not
+/// transition and did not touch the retry state are left out as replaying them would not change anything.
+pub struct Journal<Types: StateType> {
+    // When the machine was created, which replays don't otherwise learn
+    created_at: u64,
+    snapshot: Option<Snapshot>,
+    entries: Vec<JournalEntry<Types::In>>,
+}
+
+/// The state of a machine at the time the journal was compacted.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct Snapshot {
+    pub state: StateSnapshot,
+    pub is_state_initialized: bool,
+    pub step: u64,
+    pub status: MachineStatus,
+    pub retry: RetryState,
+}
+
+/// Candid encoded data of a state, turned back into a state by `StateType::restore`.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct StateSnapshot {
+    /// Name of the state, as returned by `State::name`, to tell restore which type to decode
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+impl StateSnapshot {
+    /// Encode the state's data, None when it can't be encoded
+    pub fn new<T: CandidType>(name: String, data: &T) -> Option<Self> {
+        let data = candid::encode_one(data).ok()?;
+        Some(StateSnapshot { name, data })
+    }
+
+    /// Decode the state's data, None when it is not a `T`
+    pub fn decode<T: CandidType + DeserializeOwned>(&self) -> Option<T> {
+        candid::decode_one(&self.data).ok()
+    }
+}
+
+/// A journal in a form that can be encoded, e.g. to keep it in stable memory across upgrades.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct JournalExport<In> {
+    pub created_at: u64,
+    pub snapshot: Option<Snapshot>,
+    pub entries: Vec<JournalEntry<In>>,
+}
+
+impl<Types: StateType> Default for Journal<Types> {
+    fn default() -> Self {
+        Journal {
+            created_at: 0,
+            snapshot: None,
+            entries: vec![],
+        }
+    }
+}
+
+impl<Types: StateType> Journal<Types> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Rebuild a journal from previously exported entries, e.g. after a canister upgrade.
+    pub fn from_entries(entries: Vec<JournalEntry<Types::In>>) -> Self {
+        Journal {
+            created_at: 0,
+            snapshot: None,
+            entries,
+        }
+    }
+
+    /// Rebuild a journal from an export, including its snapshot
+    pub fn from_export(export: JournalExport<Types::In>) -> Self {
+        Journal {
+            created_at: export.created_at,
+            snapshot: export.snapshot,
+            entries: export.entries,
+        }
+    }
+
+    pub fn export(&self) -> JournalExport<Types::In> {
+        JournalExport {
+            created_at: self.created_at,
+            snapshot: self.snapshot.clone(),
+            entries: self.entries.clone(),
+        }
+    }
+
+    pub fn entries(&self) -> &[JournalEntry<Types::In>] {
+        &self.entries
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// When the journaled machine was created, in nanoseconds, see `StateMachine::created_at`
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    /// Whether the journal starts from a snapshot rather than the initial state
+    pub fn has_snapshot(&self) -> bool {
+        self.snapshot.is_some()
+    }
+
+    /// The step the journal starts after, 0 unless compacted
+    pub fn base_step(&self) -> u64 {
+        self.snapshot.as_ref().map(|snapshot| snapshot.step).unwrap_or_default()
+    }
+
+    pub(crate) fn set_created_at(&mut self, created_at: u64) {
+        self.created_at = created_at;
+    }
+
+    pub(crate) fn append(&mut self, entry: JournalEntry<Types::In>) {
+        self.entries.push(entry);
+    }
+
+    pub(crate) fn compact(&mut self, snapshot: Snapshot) {
+        self.snapshot = Some(snapshot);
+        self.entries.clear();
+    }
+
+    pub fn snapshot(&self) -> Option<&Snapshot> {
+        self.snapshot.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::journal::{Journal, JournalEntry, JournalExport};
+    use crate::message_channel::create_channel;
+    use crate::state::{DeliveryStatus, State, Transition};
+    use crate::state_machine::{MachineStatus, StateMachine};
+    use crate::tests::example_2_simple_inbound_messages::{blue, red, BlueMessageState, MachineTypes, RedMessageState, SimpleMessage};
+
+    // Counts blue messages before rejecting them, so a rejected message still changes the state
+    #[derive(Debug, PartialEq)]
+    struct Tally {
+        blue: u64,
+    }
+
+    impl State<MachineTypes> for Tally {
+        fn deliver(&mut self, message: SimpleMessage) -> DeliveryStatus<SimpleMessage, String> {
+            match message {
+                SimpleMessage::IncrementBlue { .. } => {
+                    self.blue += 1;
+                    DeliveryStatus::Error("Blue is over the limit".to_string())
+                }
+                SimpleMessage::IncrementRed { .. } => DeliveryStatus::Delivered,
+            }
+        }
+
+        fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+            Ok(Transition::Same)
+        }
+    }
+
+    #[test]
+    pub fn it_replays_delivered_messages() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("simple".to_string(), sender, Box::new(RedMessageState::new()));
+        machine.enable_journal();
+
+        for message in [red("a"), red("b"), red("c"), blue("d")] {
+            handle.send(message).unwrap();
+            machine.step().unwrap();
+        }
+        // Steps without messages or transitions are not journaled
+        machine.step().unwrap();
+
+        let entries = machine.journal().unwrap().entries().to_vec();
+        assert_eq!(entries.len(), 4);
+
+        let (sender, _) = create_channel();
+        let (replayed, _) = StateMachine::replay(
+            "simple".to_string(),
+            sender,
+            Box::new(RedMessageState::new()),
+            Journal::<MachineTypes>::from_entries(entries),
+        ).unwrap();
+
+        assert_eq!(replayed.downcast_state::<BlueMessageState>(), Some(&BlueMessageState { count: 1 }));
+        assert_eq!(replayed.journal().unwrap().len(), 4);
+    }
+
+    #[test]
+    pub fn it_fails_to_replay_messages_the_state_rejects() {
+        let entries = vec![JournalEntry { step: 1, messages: vec![blue("a")], advanced: true, status: None, retry: None, delivery_error: None }];
+
+        let (sender, _) = create_channel();
+        let replayed = StateMachine::replay("simple".to_string(), sender, Box::new(RedMessageState::new()), Journal::<MachineTypes>::from_entries(entries));
+
+        assert!(replayed.is_err());
+    }
+
+    #[test]
+    pub fn it_replays_messages_answered_with_an_error() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("simple".to_string(), sender, Box::new(Tally { blue: 0 }));
+        machine.enable_journal();
+
+        handle.send(red("a")).unwrap();
+        handle.send(blue("b")).unwrap();
+        assert!(machine.step().is_err());
+
+        let journal = machine.take_journal().unwrap();
+        assert_eq!(journal.entries()[0].messages.len(), 2);
+        assert_eq!(journal.entries()[0].delivery_error, Some("Blue is over the limit".to_string()));
+
+        let (sender, _) = create_channel();
+        let (replayed, _) = StateMachine::replay("simple".to_string(), sender, Box::new(Tally { blue: 0 }), journal).unwrap();
+        assert_eq!(replayed.downcast_state::<Tally>(), Some(&Tally { blue: 1 }));
+    }
+
+    #[test]
+    pub fn it_compacts_into_a_snapshot() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("simple".to_string(), sender, Box::new(RedMessageState::new()));
+        machine.set_created_at(42);
+        machine.enable_journal();
+
+        handle.send(red("a")).unwrap();
+        machine.step().unwrap();
+        handle.send(red("b")).unwrap();
+        machine.step().unwrap();
+
+        machine.compact_journal().unwrap();
+        assert!(machine.journal().unwrap().is_empty());
+        assert_eq!(machine.journal().unwrap().base_step(), 2);
+
+        handle.send(red("c")).unwrap();
+        machine.step().unwrap();
+
+        let journal = machine.take_journal().unwrap();
+        assert_eq!(journal.len(), 1);
+
+        // The export survives encoding, as it would across an upgrade
+        let encoded = candid::encode_one(journal.export()).unwrap();
+        let export: JournalExport<SimpleMessage> = candid::decode_one(&encoded).unwrap();
+
+        let (sender, _) = create_channel();
+        let (replayed, _) = StateMachine::replay("simple".to_string(), sender, Box::new(RedMessageState::new()), Journal::<MachineTypes>::from_export(export)).unwrap();
+        assert_eq!(replayed.downcast_state::<BlueMessageState>(), Some(&BlueMessageState { count: 0 }));
+        assert_eq!(replayed.steps(), 3);
+        assert_eq!(replayed.created_at(), 42);
+    }
+
+    #[test]
+    pub fn it_compacts_after_admin_repairs() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("simple".to_string(), sender, Box::new(RedMessageState::new()));
+        machine.enable_journal();
+        handle.send(red("a")).unwrap();
+        machine.step().unwrap();
+
+        machine.edit_state::<RedMessageState>("count was lost", |red| red.count = 2).unwrap();
+        assert!(machine.journal().unwrap().is_empty());
+
+        // Blue states can't be snapshotted, so the journal could not record the transition
+        assert!(machine.force_transition(Box::new(BlueMessageState { count: 0 }), "skip red").is_err());
+        assert!(machine.downcast_state::<RedMessageState>().is_some());
+
+        let (sender, _) = create_channel();
+        let (replayed, _) = StateMachine::replay("simple".to_string(), sender, Box::new(RedMessageState::new()), machine.take_journal().unwrap()).unwrap();
+        assert_eq!(replayed.downcast_state::<RedMessageState>().map(|red| red.count), Some(2));
+    }
+
+    #[test]
+    pub fn it_replays_the_paused_status() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("simple".to_string(), sender, Box::new(RedMessageState::new()));
+        machine.enable_journal();
+
+        handle.send(red("a")).unwrap();
+        machine.step().unwrap();
+        machine.pause().unwrap();
+
+        let (sender, _) = create_channel();
+        let (mut replayed, _) = StateMachine::replay("simple".to_string(), sender, Box::new(RedMessageState::new()), machine.take_journal().unwrap()).unwrap();
+        assert_eq!(replayed.status(), &MachineStatus::Paused);
+        assert_eq!(replayed.downcast_state::<RedMessageState>(), Some(&RedMessageState { count: 1 }));
+
+        replayed.resume().unwrap();
+        assert_eq!(replayed.journal().unwrap().entries().last().unwrap().status, Some(MachineStatus::Running));
+    }
+}
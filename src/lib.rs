@@ -1,8 +1,23 @@
+#[cfg(test)]
 mod tests;
 pub mod state_machine;
 pub mod state;
 pub mod state_machine_orchestrator;
+pub mod heterogeneous_orchestrator;
 pub mod message_channel;
 pub mod message;
+pub mod clock;
+pub mod history;
+pub mod transition_table;
+pub mod saga;
+pub mod retry;
+pub mod journal;
+pub mod observer;
+pub mod logging;
+pub mod instructions;
+pub mod metrics;
+pub mod random;
+pub mod id_generator;
+pub mod idempotency;
 
 // Inspired heavily by https://github.com/vnermolaev/oblivious-state-machine/
\ No newline at end of file
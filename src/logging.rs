@@ -0,0 +1,206 @@
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::state_machine::StateMachineId;
+
+#[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Debug,
+    Info,
+    Warn,
+    Error,
+}
+
+/// A log line with the context it was written in.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub struct LogRecord {
+    pub level: LogLevel,
+    /// Nanoseconds since the unix epoch
+    pub timestamp: u64,
+    pub machine_id: Option<StateMachineId>,
+    pub state_name: Option<String>,
+    /// The `StateMachineMessage::id` of the message being handled
+    pub message_id: Option<String>,
+    pub message: String,
+}
+
+impl Display for LogRecord {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{:?}]", self.level)?;
+        if let Some(machine_id) = &self.machine_id {
+            write!(f, " machine={}", machine_id)?;
+        }
+        if let Some(state_name) = &self.state_name {
+            write!(f, " state={}", state_name)?;
+        }
+        if let Some(message_id) = &self.message_id {
+            write!(f, " message={}", message_id)?;
+        }
+        write!(f, " {}", self.message)
+    }
+}
+
+/// Backend that log records are written to.
+pub trait Logger {
+    /// Whether records of the level should be built at all
+    fn enabled(&self, _level: LogLevel) -> bool {
+        true
+    }
+
+    fn log(&self, record: LogRecord);
+}
+
+/// Writes records with `ic_cdk::println!`, which prints to the replica log inside a canister
+/// and to stdout everywhere else.
+#[derive(Debug, Clone, Copy)]
+pub struct PrintLogger {
+    min_level: LogLevel,
+}
+
+impl PrintLogger {
+    pub fn new(min_level: LogLevel) -> Self {
+        PrintLogger { min_level }
+    }
+}
+
+impl Default for PrintLogger {
+    fn default() -> Self {
+        PrintLogger::new(LogLevel::Info)
+    }
+}
+
+impl Logger for PrintLogger {
+    fn enabled(&self, level: LogLevel) -> bool {
+        level >= self.min_level
+    }
+
+    fn log(&self, record: LogRecord) {
+        ic_cdk::println!("{}", record);
+    }
+}
+
+/// Keeps the most recent records in memory so a canister can return them from a query call.
+#[derive(Debug)]
+pub struct RingBufferLogger {
+    min_level: LogLevel,
+    capacity: usize,
+    records: RefCell<VecDeque<LogRecord>>,
+}
+
+impl RingBufferLogger {
+    pub fn new(capacity: usize, min_level: LogLevel) -> Self {
+        RingBufferLogger {
+            min_level,
+            capacity,
+            records: RefCell::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Buffered records, oldest first
+    pub fn records(&self) -> Vec<LogRecord> {
+        self.records.borrow().iter().cloned().collect()
+    }
+
+    pub fn clear(&self) {
+        self.records.borrow_mut().clear();
+    }
+}
+
+impl Logger for RingBufferLogger {
+    fn enabled(&self, level: LogLevel) -> bool {
+        self.capacity > 0 && level >= self.min_level
+    }
+
+    fn log(&self, record: LogRecord) {
+        let mut records = self.records.borrow_mut();
+        if records.len() == self.capacity {
+            records.pop_front();
+        }
+        records.push_back(record);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::clock::ManualClock;
+    use crate::logging::{LogLevel, Logger, LogRecord, RingBufferLogger};
+    use crate::message_channel::create_channel;
+    use crate::state_machine::StateMachine;
+    use crate::state_machine_orchestrator::{OrchestratorError, SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::example_2_simple_inbound_messages::{MachineTypes, RedMessageState, SimpleMessage};
+
+    fn record(level: LogLevel, message: &str) -> LogRecord {
+        LogRecord {
+            level,
+            timestamp: 0,
+            machine_id: None,
+            state_name: None,
+            message_id: None,
+            message: message.to_string(),
+        }
+    }
+
+    #[test]
+    pub fn it_keeps_the_most_recent_records() {
+        let logger = RingBufferLogger::new(2, LogLevel::Info);
+
+        for message in ["one", "two", "three"] {
+            logger.log(record(LogLevel::Info, message));
+        }
+
+        let messages: Vec<String> = logger.records().into_iter().map(|r| r.message).collect();
+        assert_eq!(messages, vec!["two", "three"]);
+        assert!(!logger.enabled(LogLevel::Debug));
+        assert!(logger.enabled(LogLevel::Error));
+    }
+
+    #[test]
+    pub fn it_formats_structured_fields() {
+        let mut record = record(LogLevel::Warn, "Unexpected message");
+        record.machine_id = Some("1".to_string());
+        record.state_name = Some("Red".to_string());
+
+        assert_eq!(record.to_string(), "[Warn] machine=1 state=Red Unexpected message");
+    }
+
+    #[test]
+    pub fn it_logs_unexpected_messages_from_machines() {
+        let logger = Rc::new(RingBufferLogger::new(10, LogLevel::Debug));
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("m".to_string(), sender, Box::new(RedMessageState::new()));
+        machine.set_clock(Rc::new(ManualClock::new(42)));
+        machine.set_logger(logger.clone());
+
+        handle.send(SimpleMessage::IncrementBlue { machine_id: "m".to_string() }).unwrap();
+        assert!(machine.step().is_err());
+
+        let records = logger.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].level, LogLevel::Warn);
+        assert_eq!(records[0].timestamp, 42);
+        assert_eq!(records[0].machine_id, Some("m".to_string()));
+        assert_eq!(records[0].state_name, Some("RedMessageState".to_string()));
+        assert_eq!(records[0].message_id, Some("m".to_string()));
+    }
+
+    #[test]
+    pub fn it_logs_unroutable_messages_from_orchestrators() {
+        let logger = Rc::new(RingBufferLogger::new(10, LogLevel::Info));
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(|_| {}));
+        orchestrator.set_logger(logger.clone());
+
+        let result = orchestrator.handle_message(SimpleMessage::IncrementRed { machine_id: "missing".to_string() });
+        assert_eq!(result, Err(OrchestratorError::MachineNotFound("missing".to_string())));
+
+        let records = logger.records();
+        assert_eq!(records.len(), 1);
+        assert_eq!(records[0].message_id, Some("missing".to_string()));
+        assert_eq!(records[0].machine_id, None);
+    }
+}
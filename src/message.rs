@@ -1,4 +1,4 @@
-struct StateMachineMessage<T> {
+pub struct StateMachineMessage<T> {
     state_machine_id: String,
     message: T,
 }
@@ -1,56 +1,317 @@
 use std::collections::VecDeque;
-use std::sync::{Arc, Mutex};
+
+use shared::{new_shared, new_token, read, try_write, Shared, Token, WeakToken};
+
+/// What a bounded channel does with a message sent while it is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverflowPolicy {
+    /// Refuse the message with `SendError::Full`
+    Reject,
+    /// Drop the oldest buffered message to make room
+    DropOldest,
+    /// Drop the message being sent
+    DropNewest,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum SendError<T> {
+    /// The channel is full and its policy is `OverflowPolicy::Reject`
+    Full(T),
+    /// The channel is in use by someone else
+    Busy(T),
+    /// The receiver was dropped, the message would never be received
+    Disconnected(T),
+}
+
+impl<T> SendError<T> {
+    /// Return the message that could not be sent
+    pub fn into_inner(self) -> T {
+        match self {
+            SendError::Full(message) => message,
+            SendError::Busy(message) => message,
+            SendError::Disconnected(message) => message,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TryRecvError {
+    /// No message is buffered right now
+    Empty,
+    /// No message is buffered and every sender was dropped, so none will arrive
+    Disconnected,
+    /// The channel is in use by someone else
+    Busy,
+}
+
+struct Channel<T> {
+    buffer: VecDeque<T>,
+    capacity: Option<usize>,
+    policy: OverflowPolicy,
+    dropped: u64,
+}
+
+impl<T> Channel<T> {
+    // Buffer the message, applying the overflow policy. Returns the message when it is rejected.
+    fn push(&mut self, message: T) -> Result<(), T> {
+        if self.capacity.is_some_and(|capacity| self.buffer.len() >= capacity) {
+            match self.policy {
+                OverflowPolicy::Reject => return Err(message),
+                OverflowPolicy::DropNewest => {
+                    self.dropped += 1;
+                    return Ok(());
+                }
+                OverflowPolicy::DropOldest => {
+                    self.buffer.pop_front();
+                    self.dropped += 1;
+                }
+            }
+        }
+
+        if self.capacity != Some(0) {
+            self.buffer.push_back(message);
+        }
+        Ok(())
+    }
+}
+
+type SharedChannel<T> = Shared<Channel<T>>;
+
+// The other side of a channel is alive while any strong token it holds exists
+fn is_alive(token: &WeakToken) -> bool {
+    token.strong_count() > 0
+}
+
+// Channels are shared through Arc<Mutex<_>> so they can be used across threads. Canisters are
+// single threaded, so the `single-threaded` feature swaps in Rc<RefCell<_>> to avoid paying for atomics.
+#[cfg(not(feature = "single-threaded"))]
+mod shared {
+    use std::sync::{Arc, Mutex, MutexGuard, Weak};
+
+    pub type Shared<T> = Arc<Mutex<T>>;
+    pub type Token = Arc<()>;
+    pub type WeakToken = Weak<()>;
+
+    pub fn new_token() -> Token {
+        Arc::new(())
+    }
+
+    pub fn new_shared<T>(value: T) -> Shared<T> {
+        Arc::new(Mutex::new(value))
+    }
+
+    /// None when the value is in use
+    pub fn try_write<T>(shared: &Shared<T>) -> Option<MutexGuard<'_, T>> {
+        shared.try_lock().ok()
+    }
+
+    // Reading counters is fine on a poisoned channel
+    pub fn read<T>(shared: &Shared<T>) -> MutexGuard<'_, T> {
+        shared.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+#[cfg(feature = "single-threaded")]
+mod shared {
+    use std::cell::{Ref, RefCell, RefMut};
+    use std::rc::{Rc, Weak};
+
+    pub type Shared<T> = Rc<RefCell<T>>;
+    pub type Token = Rc<()>;
+    pub type WeakToken = Weak<()>;
+
+    pub fn new_token() -> Token {
+        Rc::new(())
+    }
+
+    pub fn new_shared<T>(value: T) -> Shared<T> {
+        Rc::new(RefCell::new(value))
+    }
+
+    /// None when the value is in use
+    pub fn try_write<T>(shared: &Shared<T>) -> Option<RefMut<'_, T>> {
+        shared.try_borrow_mut().ok()
+    }
+
+    pub fn read<T>(shared: &Shared<T>) -> Ref<'_, T> {
+        shared.borrow()
+    }
+}
 
 #[derive(Clone)]
 pub struct MessageSender<T> {
-    buffer: Arc<Mutex<VecDeque<T>>>,
+    buffer: SharedChannel<T>,
+    // Held by every sender, the receiver watches it to notice all senders are gone
+    _sender: Token,
+    receiver: WeakToken,
 }
 
 impl<T> MessageSender<T> {
-    pub fn try_send(&self, message: T) -> Result<(), ()> {
-        match self.buffer.try_lock() {
-            Ok(mut buffer) => {
-                buffer.push_back(message);
-                Ok(())
+    pub fn try_send(&self, message: T) -> Result<(), SendError<T>> {
+        self.try_send_filling(message).0
+    }
+
+    /// Send several messages at once. When the channel fills up with the `Reject` policy,
+    /// the messages that were not sent are returned in `SendError::Full`.
+    pub fn try_send_batch(&self, messages: Vec<T>) -> Result<(), SendError<Vec<T>>> {
+        self.try_send_batch_filling(messages).0
+    }
+
+    // Like `try_send`, also telling whether the channel went from empty to holding messages,
+    // checked under the same lock so the receiver is told once until it takes the messages
+    pub(crate) fn try_send_filling(&self, message: T) -> (Result<(), SendError<T>>, bool) {
+        if self.is_closed() {
+            return (Err(SendError::Disconnected(message)), false);
+        }
+
+        match try_write(&self.buffer) {
+            Some(mut channel) => {
+                let was_empty = channel.buffer.is_empty();
+                let result = channel.push(message).map_err(SendError::Full);
+                (result, was_empty && !channel.buffer.is_empty())
+            }
+            None => (Err(SendError::Busy(message)), false)
+        }
+    }
+
+    // Like `try_send_batch`, see `try_send_filling`
+    pub(crate) fn try_send_batch_filling(&self, messages: Vec<T>) -> (Result<(), SendError<Vec<T>>>, bool) {
+        if self.is_closed() {
+            return (Err(SendError::Disconnected(messages)), false);
+        }
+
+        match try_write(&self.buffer) {
+            Some(mut channel) => {
+                let was_empty = channel.buffer.is_empty();
+                let mut messages = messages.into_iter();
+                let mut result = Ok(());
+                while let Some(message) = messages.next() {
+                    if let Err(message) = channel.push(message) {
+                        result = Err(SendError::Full(std::iter::once(message).chain(messages).collect()));
+                        break;
+                    }
+                }
+                (result, was_empty && !channel.buffer.is_empty())
             }
-            Err(_) => Err(())
+            None => (Err(SendError::Busy(messages)), false)
         }
     }
+
+    /// Number of buffered messages
+    pub fn len(&self) -> usize {
+        read(&self.buffer).buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of buffered messages, None when unbounded
+    pub fn capacity(&self) -> Option<usize> {
+        read(&self.buffer).capacity
+    }
+
+    /// Number of messages dropped by the overflow policy
+    pub fn dropped(&self) -> u64 {
+        read(&self.buffer).dropped
+    }
+
+    /// Whether the receiver was dropped
+    pub fn is_closed(&self) -> bool {
+        !is_alive(&self.receiver)
+    }
 }
 
 pub struct MessageReceiver<T> {
-    buffer: Arc<Mutex<VecDeque<T>>>,
+    buffer: SharedChannel<T>,
+    // Held by the receiver, senders watch it to notice the receiver is gone
+    receiver: Token,
+    sender: WeakToken,
 }
 
 impl<T> MessageReceiver<T> {
-    pub fn try_receive(&self) -> Result<Option<T>, ()> {
-        match self.buffer.try_lock() {
-            Ok(mut buffer) => {
-                Ok(buffer.pop_front())
+    /// Take the oldest buffered message. Messages buffered before the senders were dropped are still received.
+    pub fn try_receive(&self) -> Result<T, TryRecvError> {
+        match try_write(&self.buffer) {
+            Some(mut channel) => {
+                match channel.buffer.pop_front() {
+                    Some(message) => Ok(message),
+                    None if self.is_disconnected() => Err(TryRecvError::Disconnected),
+                    None => Err(TryRecvError::Empty),
+                }
             }
-            Err(_) => Err(())
+            None => Err(TryRecvError::Busy)
         }
     }
+
+    /// Number of buffered messages
+    pub fn len(&self) -> usize {
+        read(&self.buffer).buffer.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Maximum number of buffered messages, None when unbounded
+    pub fn capacity(&self) -> Option<usize> {
+        read(&self.buffer).capacity
+    }
+
+    /// Number of messages dropped by the overflow policy
+    pub fn dropped(&self) -> u64 {
+        read(&self.buffer).dropped
+    }
+
+    /// Whether every sender was dropped
+    pub fn is_disconnected(&self) -> bool {
+        !is_alive(&self.sender)
+    }
 }
 
 
 pub fn create_channel<T>() -> (MessageSender<T>, MessageReceiver<T>) {
-    let buffer = Arc::new(Mutex::new(VecDeque::new()));
-    let sender = MessageSender { buffer: buffer.clone() };
-    let receiver = MessageReceiver { buffer };
+    new_channel(None, OverflowPolicy::Reject)
+}
+
+/// Create a channel that buffers at most `capacity` messages, applying `policy` to messages sent while full.
+pub fn create_bounded_channel<T>(capacity: usize, policy: OverflowPolicy) -> (MessageSender<T>, MessageReceiver<T>) {
+    new_channel(Some(capacity), policy)
+}
+
+fn new_channel<T>(capacity: Option<usize>, policy: OverflowPolicy) -> (MessageSender<T>, MessageReceiver<T>) {
+    let buffer = new_shared(Channel {
+        buffer: VecDeque::new(),
+        capacity,
+        policy,
+        dropped: 0,
+    });
+    let sender_token = new_token();
+    let receiver_token = new_token();
+
+    let receiver = MessageReceiver {
+        buffer: buffer.clone(),
+        sender: Token::downgrade(&sender_token),
+        receiver: receiver_token,
+    };
+    let sender = MessageSender {
+        buffer,
+        receiver: Token::downgrade(&receiver.receiver),
+        _sender: sender_token,
+    };
     (sender, receiver)
 }
 
 #[cfg(test)]
 mod test {
-    use crate::message_channel::create_channel;
+    use crate::message_channel::{create_bounded_channel, create_channel, OverflowPolicy, SendError, TryRecvError};
 
     #[test]
     pub fn it_sends_and_receives_a_message() {
         let (tx, rx) = create_channel::<u64>();
 
         tx.try_send(1).unwrap();
-        assert_eq!(rx.try_receive().unwrap(), Some(1));
+        assert_eq!(rx.try_receive(), Ok(1));
     }
 
     #[test]
@@ -60,11 +321,118 @@ mod test {
         let tx_1 = tx.clone();
 
         tx.try_send(1).unwrap();
-        assert_eq!(rx.try_receive().unwrap(), Some(1));
+        assert_eq!(rx.try_receive(), Ok(1));
 
         tx_1.try_send(2).unwrap();
         tx.try_send(3).unwrap();
-        assert_eq!(rx.try_receive().unwrap(), Some(2));
-        assert_eq!(rx.try_receive().unwrap(), Some(3));
+        assert_eq!(rx.try_receive(), Ok(2));
+        assert_eq!(rx.try_receive(), Ok(3));
+    }
+
+    #[test]
+    pub fn it_reports_busy_while_in_use() {
+        let (tx, rx) = create_channel::<u64>();
+
+        let guard = super::try_write(&rx.buffer);
+        assert_eq!(tx.try_send(1), Err(SendError::Busy(1)));
+        assert_eq!(rx.try_receive(), Err(TryRecvError::Busy));
+
+        drop(guard);
+        tx.try_send(1).unwrap();
+        assert_eq!(rx.try_receive(), Ok(1));
+    }
+
+    #[test]
+    pub fn it_rejects_messages_when_full() {
+        let (tx, rx) = create_bounded_channel::<u64>(2, OverflowPolicy::Reject);
+
+        tx.try_send(1).unwrap();
+        tx.try_send(2).unwrap();
+        assert_eq!(tx.try_send(3), Err(SendError::Full(3)));
+        assert_eq!(tx.len(), 2);
+        assert_eq!(rx.capacity(), Some(2));
+
+        assert_eq!(rx.try_receive(), Ok(1));
+        tx.try_send(3).unwrap();
+        assert_eq!(rx.len(), 2);
+    }
+
+    #[test]
+    pub fn it_drops_the_oldest_message_when_full() {
+        let (tx, rx) = create_bounded_channel::<u64>(2, OverflowPolicy::DropOldest);
+
+        for message in 1..=4 {
+            tx.try_send(message).unwrap();
+        }
+
+        assert_eq!(rx.dropped(), 2);
+        assert_eq!(rx.try_receive(), Ok(3));
+        assert_eq!(rx.try_receive(), Ok(4));
+    }
+
+    #[test]
+    pub fn it_drops_the_newest_message_when_full() {
+        let (tx, rx) = create_bounded_channel::<u64>(2, OverflowPolicy::DropNewest);
+
+        for message in 1..=4 {
+            tx.try_send(message).unwrap();
+        }
+
+        assert_eq!(tx.dropped(), 2);
+        assert_eq!(rx.try_receive(), Ok(1));
+        assert_eq!(rx.try_receive(), Ok(2));
+        assert_eq!(rx.try_receive(), Err(TryRecvError::Empty));
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn it_tells_only_the_first_message_sent_to_an_empty_channel() {
+        let (tx, rx) = create_bounded_channel::<u64>(2, OverflowPolicy::DropOldest);
+
+        let filled: Vec<bool> = (1..=100).map(|message| tx.try_send_filling(message).1).collect();
+        assert_eq!(filled.iter().filter(|filled| **filled).count(), 1);
+        assert!(filled[0]);
+        assert!(!tx.try_send_batch_filling(vec![101, 102]).1);
+
+        while rx.try_receive().is_ok() {}
+        assert!(tx.try_send_batch_filling(vec![103]).1);
+    }
+
+    #[test]
+    pub fn it_sends_batches_until_full() {
+        let (tx, rx) = create_bounded_channel::<u64>(3, OverflowPolicy::Reject);
+
+        tx.try_send_batch(vec![1, 2]).unwrap();
+        assert_eq!(tx.try_send_batch(vec![3, 4, 5]), Err(SendError::Full(vec![4, 5])));
+        assert_eq!(rx.try_receive(), Ok(1));
+        assert_eq!(rx.try_receive(), Ok(2));
+        assert_eq!(rx.try_receive(), Ok(3));
+    }
+
+    #[test]
+    pub fn it_detects_a_dropped_receiver() {
+        let (tx, rx) = create_channel::<u64>();
+        assert!(!tx.is_closed());
+
+        drop(rx);
+        assert!(tx.is_closed());
+        assert_eq!(tx.try_send(1), Err(SendError::Disconnected(1)));
+    }
+
+    #[test]
+    pub fn it_detects_dropped_senders_after_draining() {
+        let (tx, rx) = create_channel::<u64>();
+        let tx_1 = tx.clone();
+
+        tx.try_send(1).unwrap();
+        drop(tx);
+        assert!(!rx.is_disconnected());
+        assert_eq!(rx.try_receive(), Ok(1));
+        assert_eq!(rx.try_receive(), Err(TryRecvError::Empty));
+
+        tx_1.try_send(2).unwrap();
+        drop(tx_1);
+        assert!(rx.is_disconnected());
+        assert_eq!(rx.try_receive(), Ok(2));
+        assert_eq!(rx.try_receive(), Err(TryRecvError::Disconnected));
+    }
+}
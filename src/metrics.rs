@@ -0,0 +1,225 @@
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::rc::Rc;
+
+use crate::clock::Clock;
+use crate::observer::StateMachineObserver;
+use crate::state::{State, StateType};
+use crate::state_machine::{StateMachineError, StateMachineId};
+
+/// Counters collected by an orchestrator while stepping its machines.
+///
+/// Registered as an observer on every machine, so it sees transitions, deliveries and errors.
+/// Unroutable messages and instruction counts are reported by the orchestrator itself.
+pub struct OrchestratorMetrics {
+    clock: Rc<dyn Clock>,
+    counters: RefCell<Counters>,
+}
+
+#[derive(Default)]
+struct Counters {
+    transitions: BTreeMap<(String, String), u64>,
+    messages_delivered: u64,
+    messages_rejected: u64,
+    messages_unroutable: u64,
+    step_errors: u64,
+    time_in_state: BTreeMap<String, u64>,
+    step_instructions_sum: u64,
+    step_instructions_count: u64,
+    // State each machine is in and when it was entered
+    entered: HashMap<StateMachineId, (String, u64)>,
+    // State each machine left and has not entered the next state of yet
+    exited: HashMap<StateMachineId, String>,
+}
+
+impl OrchestratorMetrics {
+    pub fn new(clock: Rc<dyn Clock>) -> Self {
+        OrchestratorMetrics {
+            clock,
+            counters: RefCell::new(Counters::default()),
+        }
+    }
+
+    /// Number of transitions between each pair of state names
+    pub fn transitions(&self) -> BTreeMap<(String, String), u64> {
+        self.counters.borrow().transitions.clone()
+    }
+
+    pub fn messages_delivered(&self) -> u64 {
+        self.counters.borrow().messages_delivered
+    }
+
+    pub fn messages_rejected(&self) -> u64 {
+        self.counters.borrow().messages_rejected
+    }
+
+    pub fn messages_unroutable(&self) -> u64 {
+        self.counters.borrow().messages_unroutable
+    }
+
+    pub fn step_errors(&self) -> u64 {
+        self.counters.borrow().step_errors
+    }
+
+    /// Nanoseconds machines spent in each state, counted when the state is left
+    pub fn time_in_state(&self) -> BTreeMap<String, u64> {
+        self.counters.borrow().time_in_state.clone()
+    }
+
+    /// Total instructions spent stepping machines and the number of steps measured
+    pub fn step_instructions(&self) -> (u64, u64) {
+        let counters = self.counters.borrow();
+        (counters.step_instructions_sum, counters.step_instructions_count)
+    }
+
+    pub(crate) fn record_unroutable(&self) {
+        self.counters.borrow_mut().messages_unroutable += 1;
+    }
+
+    pub(crate) fn record_step_instructions(&self, instructions: u64) {
+        let mut counters = self.counters.borrow_mut();
+        counters.step_instructions_sum += instructions;
+        counters.step_instructions_count += 1;
+    }
+
+    /// Render the metrics in the Prometheus text exposition format.
+    /// `machines_by_state` is the number of machines currently in each state.
+    pub fn render_prometheus(&self, machines_by_state: &BTreeMap<String, u64>) -> String {
+        let counters = self.counters.borrow();
+        let mut out = String::new();
+
+        header(&mut out, "state_machine_machines", "gauge", "Machines by current state");
+        for (state, count) in machines_by_state {
+            let _ = writeln!(out, "state_machine_machines{{state=\"{}\"}} {}", escape(state), count);
+        }
+
+        header(&mut out, "state_machine_transitions_total", "counter", "Transitions between states");
+        for ((from, to), count) in &counters.transitions {
+            let _ = writeln!(out, "state_machine_transitions_total{{from=\"{}\",to=\"{}\"}} {}", escape(from), escape(to), count);
+        }
+
+        header(&mut out, "state_machine_messages_total", "counter", "Inbound messages by outcome");
+        let _ = writeln!(out, "state_machine_messages_total{{outcome=\"delivered\"}} {}", counters.messages_delivered);
+        let _ = writeln!(out, "state_machine_messages_total{{outcome=\"rejected\"}} {}", counters.messages_rejected);
+        let _ = writeln!(out, "state_machine_messages_total{{outcome=\"unroutable\"}} {}", counters.messages_unroutable);
+
+        header(&mut out, "state_machine_step_errors_total", "counter", "Failed steps");
+        let _ = writeln!(out, "state_machine_step_errors_total {}", counters.step_errors);
+
+        header(&mut out, "state_machine_time_in_state_nanoseconds_total", "counter", "Time spent in states that were left");
+        for (state, nanos) in &counters.time_in_state {
+            let _ = writeln!(out, "state_machine_time_in_state_nanoseconds_total{{state=\"{}\"}} {}", escape(state), nanos);
+        }
+
+        header(&mut out, "state_machine_step_instructions", "summary", "Instructions used per step");
+        let _ = writeln!(out, "state_machine_step_instructions_sum {}", counters.step_instructions_sum);
+        let _ = writeln!(out, "state_machine_step_instructions_count {}", counters.step_instructions_count);
+
+        out
+    }
+
+    fn leave_state(&self, machine_id: &StateMachineId) -> Option<String> {
+        let now = self.clock.now();
+        let mut counters = self.counters.borrow_mut();
+        let (state, entered_at) = counters.entered.remove(machine_id)?;
+        *counters.time_in_state.entry(state.clone()).or_default() += now.saturating_sub(entered_at);
+        Some(state)
+    }
+}
+
+impl<Types: StateType> StateMachineObserver<Types> for OrchestratorMetrics {
+    fn on_state_entered(&self, machine_id: &StateMachineId, state: &dyn State<Types>) {
+        let now = self.clock.now();
+        let name = state.name();
+        let mut counters = self.counters.borrow_mut();
+
+        if let Some(from) = counters.exited.remove(machine_id) {
+            *counters.transitions.entry((from, name.clone())).or_default() += 1;
+        }
+        counters.entered.insert(machine_id.clone(), (name, now));
+    }
+
+    fn on_state_exited(&self, machine_id: &StateMachineId, _state: &dyn State<Types>) {
+        if let Some(state) = self.leave_state(machine_id) {
+            self.counters.borrow_mut().exited.insert(machine_id.clone(), state);
+        }
+    }
+
+    fn on_message_delivered(&self, _machine_id: &StateMachineId, _message: &Types::In) {
+        self.counters.borrow_mut().messages_delivered += 1;
+    }
+
+    fn on_message_rejected(&self, _machine_id: &StateMachineId, _message: &Types::In, _error: &StateMachineError) {
+        self.counters.borrow_mut().messages_rejected += 1;
+    }
+
+    fn on_step_error(&self, _machine_id: &StateMachineId, _error: &StateMachineError) {
+        self.counters.borrow_mut().step_errors += 1;
+    }
+
+    fn on_terminated(&self, machine_id: &StateMachineId, _state: &dyn State<Types>) {
+        self.leave_state(machine_id);
+    }
+
+    fn on_cancelled(&self, machine_id: &StateMachineId, _state: &dyn State<Types>, _reason: &str) {
+        self.leave_state(machine_id);
+    }
+}
+
+fn header(out: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} {}", name, kind);
+}
+
+fn escape(label: &str) -> String {
+    label.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::clock::ManualClock;
+    use crate::state_machine_orchestrator::{SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::example_2_simple_inbound_messages::{blue, red, MachineTypes, RedMessageState};
+    use crate::tests::support::SteadyInstructionCounter;
+
+    #[test]
+    pub fn it_collects_orchestrator_metrics() {
+        let clock = ManualClock::new(0);
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(|_| {}));
+        orchestrator.set_clock(Rc::new(clock.clone()));
+        orchestrator.set_instruction_counter(Rc::new(SteadyInstructionCounter::default()));
+        orchestrator.enable_metrics();
+
+        let (one, _) = orchestrator.create_machine(Box::new(RedMessageState::new())).unwrap();
+        let (two, _) = orchestrator.create_machine(Box::new(RedMessageState::new())).unwrap();
+
+        for _ in 0..3 {
+            orchestrator.handle_message(red(&one)).unwrap();
+            clock.advance(10);
+        }
+        assert!(orchestrator.handle_message(red(&one)).is_err());
+        orchestrator.handle_message(blue(&one)).unwrap();
+        assert!(orchestrator.handle_message(blue("missing")).is_err());
+        // Admin actions are not steps
+        orchestrator.cancel(&two, "no longer needed").unwrap();
+
+        let metrics = orchestrator.metrics().unwrap();
+        assert_eq!(metrics.messages_delivered(), 4);
+        assert_eq!(metrics.messages_rejected(), 1);
+        assert_eq!(metrics.messages_unroutable(), 1);
+        assert_eq!(metrics.step_errors(), 1);
+        assert_eq!(metrics.time_in_state().get("RedMessageState"), Some(&20));
+        assert_eq!(metrics.transitions().get(&("RedMessageState".to_string(), "BlueMessageState".to_string())), Some(&1));
+        assert_eq!(metrics.step_instructions(), (500, 5));
+
+        let rendered = orchestrator.render_prometheus_metrics().unwrap();
+        assert!(rendered.contains("state_machine_machines{state=\"BlueMessageState\"} 1\n"));
+        assert!(rendered.contains("state_machine_machines{state=\"RedMessageState\"} 1\n"));
+        assert!(rendered.contains("state_machine_transitions_total{from=\"RedMessageState\",to=\"BlueMessageState\"} 1\n"));
+        assert!(rendered.contains("state_machine_messages_total{outcome=\"unroutable\"} 1\n"));
+        assert!(rendered.contains("# TYPE state_machine_step_instructions summary\n"));
+    }
+}
@@ -0,0 +1,147 @@
+use crate::history::AdminAction;
+use crate::state::{State, StateType};
+use crate::state_machine::{StateMachineError, StateMachineId};
+
+/// Receives lifecycle events from state machines, e.g. to feed metrics or logs.
+/// All callbacks default to doing nothing.
+pub trait StateMachineObserver<Types: StateType> {
+    /// The state was initialized, which happens on the first step after it became current
+    fn on_state_entered(&self, _machine_id: &StateMachineId, _state: &dyn State<Types>) {}
+
+    /// The state was replaced by the next one
+    fn on_state_exited(&self, _machine_id: &StateMachineId, _state: &dyn State<Types>) {}
+
+    fn on_message_delivered(&self, _machine_id: &StateMachineId, _message: &Types::In) {}
+
+    /// The state did not accept the message, or failed to handle it
+    fn on_message_rejected(&self, _machine_id: &StateMachineId, _message: &Types::In, _error: &StateMachineError) {}
+
+    fn on_outbound_emitted(&self, _machine_id: &StateMachineId, _message: &Types::Out) {}
+
+    /// A step failed, either delivering a message or advancing the state, including attempts the retry policy retries
+    fn on_step_error(&self, _machine_id: &StateMachineId, _error: &StateMachineError) {}
+
+    /// The state returned `Transition::Terminal`
+    fn on_terminated(&self, _machine_id: &StateMachineId, _state: &dyn State<Types>) {}
+
+    /// The machine was cancelled while in the state
+    fn on_cancelled(&self, _machine_id: &StateMachineId, _state: &dyn State<Types>, _reason: &str) {}
+
+    /// An administrator repaired the machine, which is now in the state
+    fn on_admin_action(&self, _machine_id: &StateMachineId, _state: &dyn State<Types>, _action: &AdminAction) {}
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::history::AdminAction;
+    use crate::message_channel::create_channel;
+    use crate::observer::StateMachineObserver;
+    use crate::state::{State, StateType};
+    use crate::state_machine::{StateMachine, StateMachineError, StateMachineId};
+    use crate::state_machine_orchestrator::{SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::example_2_simple_inbound_messages::{blue, red, BlueMessageState, MachineTypes, RedMessageState};
+
+    #[derive(Default)]
+    struct RecordingObserver {
+        events: RefCell<Vec<String>>,
+    }
+
+    impl<Types: StateType> StateMachineObserver<Types> for RecordingObserver {
+        fn on_state_entered(&self, machine_id: &StateMachineId, state: &dyn State<Types>) {
+            self.events.borrow_mut().push(format!("{} entered {}", machine_id, state.name()));
+        }
+
+        fn on_state_exited(&self, machine_id: &StateMachineId, state: &dyn State<Types>) {
+            self.events.borrow_mut().push(format!("{} exited {}", machine_id, state.name()));
+        }
+
+        fn on_message_delivered(&self, machine_id: &StateMachineId, _message: &Types::In) {
+            self.events.borrow_mut().push(format!("{} delivered", machine_id));
+        }
+
+        fn on_message_rejected(&self, machine_id: &StateMachineId, _message: &Types::In, _error: &StateMachineError) {
+            self.events.borrow_mut().push(format!("{} rejected", machine_id));
+        }
+
+        fn on_step_error(&self, machine_id: &StateMachineId, _error: &StateMachineError) {
+            self.events.borrow_mut().push(format!("{} error", machine_id));
+        }
+
+        fn on_terminated(&self, machine_id: &StateMachineId, state: &dyn State<Types>) {
+            self.events.borrow_mut().push(format!("{} terminated in {}", machine_id, state.name()));
+        }
+
+        fn on_admin_action(&self, machine_id: &StateMachineId, state: &dyn State<Types>, action: &AdminAction) {
+            self.events.borrow_mut().push(format!("{} repaired into {}: {:?}", machine_id, state.name(), action));
+        }
+    }
+
+    #[test]
+    pub fn it_notifies_machine_observers() {
+        let observer = Rc::new(RecordingObserver::default());
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("m".to_string(), sender, Box::new(RedMessageState::new()));
+        machine.add_observer(observer.clone());
+
+        for message in [red("m"), red("m"), red("m"), blue("m"), red("m")] {
+            handle.send(message).unwrap();
+            let _ = machine.step();
+        }
+        handle.send(blue("m")).unwrap();
+        machine.step().unwrap();
+
+        assert_eq!(*observer.events.borrow(), vec![
+            "m entered RedMessageState",
+            "m delivered",
+            "m delivered",
+            "m delivered",
+            "m exited RedMessageState",
+            "m entered BlueMessageState",
+            "m delivered",
+            "m rejected",
+            "m error",
+            "m delivered",
+            "m terminated in BlueMessageState",
+        ]);
+    }
+
+    #[test]
+    pub fn it_notifies_admin_actions() {
+        let observer = Rc::new(RecordingObserver::default());
+        let (sender, _) = create_channel();
+        let (mut machine, _) = StateMachine::new("m".to_string(), sender, Box::new(RedMessageState::new()));
+        machine.add_observer(observer.clone());
+
+        machine.force_transition(Box::new(BlueMessageState { count: 0 }), "skip red").unwrap();
+        machine.edit_state::<BlueMessageState>("start at one", |blue| blue.count = 1).unwrap();
+
+        assert_eq!(*observer.events.borrow(), vec![
+            "m exited RedMessageState",
+            "m repaired into BlueMessageState: ForceTransition { reason: \"skip red\" }",
+            "m repaired into BlueMessageState: EditState { reason: \"start at one\" }",
+        ]);
+    }
+
+    #[test]
+    pub fn it_notifies_orchestrator_observers() {
+        let observer = Rc::new(RecordingObserver::default());
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(|_| {}));
+
+        let (before, _) = orchestrator.create_machine(Box::new(RedMessageState::new())).unwrap();
+        orchestrator.add_observer(observer.clone());
+        let (after, _) = orchestrator.create_machine(Box::new(RedMessageState::new())).unwrap();
+
+        orchestrator.handle_message(red(&before)).unwrap();
+        orchestrator.handle_message(red(&after)).unwrap();
+
+        assert_eq!(*observer.events.borrow(), vec![
+            format!("{} entered RedMessageState", before),
+            format!("{} delivered", before),
+            format!("{} entered RedMessageState", after),
+            format!("{} delivered", after),
+        ]);
+    }
+}
@@ -0,0 +1,106 @@
+/// Small deterministic random number generator (xoshiro256**), so canisters can draw
+/// random values from a seed obtained once, e.g. from `raw_rand`.
+/// Not suitable for cryptographic purposes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SeededRng {
+    state: [u64; 4],
+}
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        // Expand the seed with splitmix64, which never yields the all zero state
+        let mut seed = seed;
+        let mut state = [0; 4];
+        for word in state.iter_mut() {
+            seed = seed.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = seed;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            *word = z ^ (z >> 31);
+        }
+        SeededRng { state }
+    }
+
+    /// Seed from arbitrary bytes, such as the 32 bytes returned by `raw_rand`
+    pub fn from_bytes(bytes: &[u8]) -> Self {
+        let seed = bytes.iter().fold(0xCBF2_9CE4_8422_2325u64, |hash, byte| (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01B3));
+        SeededRng::new(seed)
+    }
+
+    /// Seed from the management canister's `raw_rand`
+    #[cfg(target_arch = "wasm32")]
+    pub async fn from_raw_rand() -> Result<Self, String> {
+        let (bytes,) = ic_cdk::api::management_canister::main::raw_rand()
+            .await
+            .map_err(|(code, message)| format!("raw_rand failed: {:?} {}", code, message))?;
+        Ok(SeededRng::from_bytes(&bytes))
+    }
+
+    /// The generator's internal state, to persist it and continue the same sequence with `from_state`
+    pub fn state(&self) -> Vec<u64> {
+        self.state.to_vec()
+    }
+
+    /// Continue from a state returned by `state`. None unless it has four words, not all zero.
+    pub fn from_state(state: &[u64]) -> Option<Self> {
+        let state: [u64; 4] = state.try_into().ok()?;
+        if state == [0; 4] {
+            return None;
+        }
+        Some(SeededRng { state })
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        let result = self.state[1].wrapping_mul(5).rotate_left(7).wrapping_mul(9);
+        let t = self.state[1] << 17;
+
+        self.state[2] ^= self.state[0];
+        self.state[3] ^= self.state[1];
+        self.state[1] ^= self.state[2];
+        self.state[0] ^= self.state[3];
+        self.state[2] ^= t;
+        self.state[3] = self.state[3].rotate_left(45);
+
+        result
+    }
+
+    /// Uniformly distributed in [0, 1)
+    pub fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    pub fn fill_bytes(&mut self, bytes: &mut [u8]) {
+        for chunk in bytes.chunks_mut(8) {
+            let random = self.next_u64().to_le_bytes();
+            chunk.copy_from_slice(&random[..chunk.len()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::random::SeededRng;
+
+    #[test]
+    pub fn it_is_deterministic_per_seed() {
+        let mut one = SeededRng::new(7);
+        let mut two = SeededRng::new(7);
+        let mut other = SeededRng::from_bytes(&[7]);
+
+        let drawn: Vec<u64> = (0..4).map(|_| one.next_u64()).collect();
+        assert_eq!(drawn, (0..4).map(|_| two.next_u64()).collect::<Vec<u64>>());
+        assert_ne!(drawn, (0..4).map(|_| other.next_u64()).collect::<Vec<u64>>());
+        assert!((0..100).map(|_| one.next_f64()).all(|value| (0.0..1.0).contains(&value)));
+    }
+
+    #[test]
+    pub fn it_continues_from_an_exported_state() {
+        let mut rng = SeededRng::new(7);
+        rng.next_u64();
+        let mut restored = SeededRng::from_state(&rng.state()).unwrap();
+
+        assert_eq!(restored.next_u64(), rng.next_u64());
+        assert_eq!(SeededRng::from_state(&[0; 4]), None);
+        assert_eq!(SeededRng::from_state(&[1, 2]), None);
+    }
+}
@@ -0,0 +1,103 @@
+use std::rc::Rc;
+
+use crate::random::SeededRng;
+
+/// Decides from the error message whether a failed attempt may be retried
+pub type RetryClassifier = Rc<dyn Fn(&str) -> bool>;
+
+/// How a state's failing `advance` is retried: up to `max_attempts` attempts in total, waiting an exponentially
+/// growing backoff between them. Errors the policy doesn't consider retryable fail the machine immediately.
+#[derive(Clone)]
+pub struct RetryPolicy {
+    max_attempts: u32,
+    initial_backoff: u64,
+    multiplier: u32,
+    max_backoff: u64,
+    jitter: f64,
+    retryable: Option<RetryClassifier>,
+}
+
+impl RetryPolicy {
+    /// Retry every error, doubling the backoff after each attempt. Backoffs are in nanoseconds.
+    pub fn new(max_attempts: u32, initial_backoff: u64) -> Self {
+        RetryPolicy {
+            max_attempts,
+            initial_backoff,
+            multiplier: 2,
+            max_backoff: u64::MAX,
+            jitter: 0.0,
+            retryable: None,
+        }
+    }
+
+    pub fn with_multiplier(mut self, multiplier: u32) -> Self {
+        self.multiplier = multiplier;
+        self
+    }
+
+    pub fn with_max_backoff(mut self, max_backoff: u64) -> Self {
+        self.max_backoff = max_backoff;
+        self
+    }
+
+    /// Shorten each backoff by a random fraction of up to `jitter`, between 0 and 1,
+    /// so machines that failed together don't retry together
+    pub fn with_jitter(mut self, jitter: f64) -> Self {
+        self.jitter = jitter.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Only retry errors `retryable` returns true for
+    pub fn with_retryable(mut self, retryable: impl Fn(&str) -> bool + 'static) -> Self {
+        self.retryable = Some(Rc::new(retryable));
+        self
+    }
+
+    pub fn max_attempts(&self) -> u32 {
+        self.max_attempts
+    }
+
+    pub fn is_retryable(&self, error: &str) -> bool {
+        self.retryable.as_ref().is_none_or(|retryable| retryable(error))
+    }
+
+    /// Nanoseconds to wait after the failed attempt with the number, starting at 1
+    pub fn backoff(&self, attempt: u32, rng: &mut SeededRng) -> u64 {
+        let factor = (self.multiplier as u64).saturating_pow(attempt.saturating_sub(1));
+        let backoff = self.initial_backoff.saturating_mul(factor).min(self.max_backoff);
+        let jitter = (backoff as f64 * self.jitter * rng.next_f64()) as u64;
+        backoff - jitter.min(backoff)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::random::SeededRng;
+    use crate::retry::RetryPolicy;
+
+    #[test]
+    pub fn it_backs_off_exponentially() {
+        let policy = RetryPolicy::new(5, 100).with_max_backoff(500);
+        let mut rng = SeededRng::new(0);
+
+        let backoffs: Vec<u64> = (1..=4).map(|attempt| policy.backoff(attempt, &mut rng)).collect();
+        assert_eq!(backoffs, vec![100, 200, 400, 500]);
+    }
+
+    #[test]
+    pub fn it_applies_jitter() {
+        let policy = RetryPolicy::new(5, 1000).with_jitter(0.5);
+        let mut rng = SeededRng::new(0);
+
+        assert!((0..100).map(|_| policy.backoff(1, &mut rng)).all(|backoff| (500..=1000).contains(&backoff)));
+    }
+
+    #[test]
+    pub fn it_classifies_retryable_errors() {
+        let policy = RetryPolicy::new(3, 10).with_retryable(|error| error.starts_with("Timeout"));
+
+        assert!(policy.is_retryable("Timeout calling ledger"));
+        assert!(!policy.is_retryable("Insufficient funds"));
+        assert!(RetryPolicy::new(3, 10).is_retryable("Insufficient funds"));
+    }
+}
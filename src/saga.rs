@@ -0,0 +1,418 @@
+use std::fmt::{Debug, Formatter};
+use std::rc::Rc;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::journal::StateSnapshot;
+use crate::state::{BoxedState, DeliveryStatus, State, StateType, Transition};
+
+/// Name of saga snapshots. Saga states are named after their step, so snapshots are named after the type
+/// for `StateType::restore` to tell them apart.
+pub const SAGA_SNAPSHOT_NAME: &str = "SagaState";
+
+/// Outcome of a saga step or compensation, reported by an inbound message
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub enum SagaEvent {
+    Completed,
+    Failed(String),
+}
+
+/// Where a saga is, as seen from its current state
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub enum SagaPhase {
+    /// The action of the step was emitted and the saga waits for its outcome
+    Running { step: usize },
+    /// A step failed and the compensation of the step was emitted, the saga waits for its outcome
+    Compensating { step: usize, error: String },
+    /// Every step completed
+    Completed,
+    /// A step failed and every completed step was compensated
+    Compensated { error: String },
+    /// A step failed and the compensation of `step` failed too, the steps before it were not compensated
+    CompensationFailed { step: usize, error: String, compensation_error: String },
+}
+
+#[derive(CandidType, Deserialize)]
+struct SagaSnapshot {
+    phase: SagaPhase,
+    outcome: Option<SagaEvent>,
+}
+
+type Command<Types> = Box<dyn Fn() -> <Types as StateType>::Out>;
+type Classifier<Types> = Box<dyn Fn(&<Types as StateType>::In) -> Option<SagaEvent>>;
+
+struct SagaStep<Types: StateType> {
+    name: String,
+    action: Command<Types>,
+    compensation: Command<Types>,
+}
+
+struct SagaDefinition<Types: StateType> {
+    steps: Vec<SagaStep<Types>>,
+    classifier: Classifier<Types>,
+}
+
+/// Builds a saga: steps run in order, each emitting its action and waiting for an inbound message that
+/// reports the outcome. When a step fails, the compensations of the completed steps run in reverse order,
+/// each waiting for its outcome in turn. A failed compensation stops the saga in `SagaPhase::CompensationFailed`.
+pub struct SagaBuilder<Types: StateType> {
+    steps: Vec<SagaStep<Types>>,
+}
+
+impl<Types: StateType> SagaBuilder<Types> {
+    pub fn new() -> Self {
+        SagaBuilder { steps: vec![] }
+    }
+
+    /// Add a step that emits `action` when it starts and `compensation` when it has to be undone
+    pub fn step(
+        mut self,
+        name: &str,
+        action: impl Fn() -> Types::Out + 'static,
+        compensation: impl Fn() -> Types::Out + 'static,
+    ) -> Self {
+        self.steps.push(SagaStep {
+            name: name.to_string(),
+            action: Box::new(action),
+            compensation: Box::new(compensation),
+        });
+        self
+    }
+
+    /// Finish the saga. `classifier` tells the outcome of the running step or compensation from an inbound message,
+    /// messages it returns None for are rejected as unexpected.
+    /// Returns the initial state of the saga, or None when it has no steps.
+    pub fn build(self, classifier: impl Fn(&Types::In) -> Option<SagaEvent> + 'static) -> Option<BoxedState<Types>> {
+        if self.steps.is_empty() {
+            return None;
+        }
+
+        let definition = self.definition(classifier);
+        Some(Box::new(SagaState::new(definition, SagaPhase::Running { step: 0 })))
+    }
+
+    /// Rebuild a saga state from its snapshot, for `StateType::restore`. The steps and classifier must match
+    /// the saga the snapshot was taken from. None when the snapshot is not of a saga with as many steps.
+    pub fn restore(self, classifier: impl Fn(&Types::In) -> Option<SagaEvent> + 'static, snapshot: &StateSnapshot) -> Option<BoxedState<Types>> {
+        if snapshot.name != SAGA_SNAPSHOT_NAME {
+            return None;
+        }
+
+        let SagaSnapshot { phase, outcome } = snapshot.decode()?;
+        let step = match &phase {
+            SagaPhase::Running { step } | SagaPhase::Compensating { step, .. } | SagaPhase::CompensationFailed { step, .. } => Some(*step),
+            SagaPhase::Completed | SagaPhase::Compensated { .. } => None,
+        };
+        if step.is_some_and(|step| step >= self.steps.len()) {
+            return None;
+        }
+
+        let definition = self.definition(classifier);
+        Some(Box::new(SagaState { definition, phase, outcome }))
+    }
+
+    fn definition(self, classifier: impl Fn(&Types::In) -> Option<SagaEvent> + 'static) -> Rc<SagaDefinition<Types>> {
+        Rc::new(SagaDefinition {
+            steps: self.steps,
+            classifier: Box::new(classifier),
+        })
+    }
+}
+
+impl<Types: StateType> Default for SagaBuilder<Types> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A state of a saga built with `SagaBuilder`. Each phase of the saga is a separate state,
+/// so every step and compensation is recorded as a transition.
+pub struct SagaState<Types: StateType> {
+    definition: Rc<SagaDefinition<Types>>,
+    phase: SagaPhase,
+    outcome: Option<SagaEvent>,
+}
+
+impl<Types: StateType> SagaState<Types> {
+    fn new(definition: Rc<SagaDefinition<Types>>, phase: SagaPhase) -> Self {
+        SagaState {
+            definition,
+            phase,
+            outcome: None,
+        }
+    }
+
+    pub fn phase(&self) -> &SagaPhase {
+        &self.phase
+    }
+
+    fn next(&self, phase: SagaPhase) -> Transition<Types> {
+        Transition::Next(Box::new(SagaState::new(self.definition.clone(), phase)))
+    }
+
+    // Undo the steps before `step`, last one first
+    fn compensate_before(&self, step: usize, error: String) -> Transition<Types> {
+        match step {
+            0 => self.next(SagaPhase::Compensated { error }),
+            step => self.next(SagaPhase::Compensating { step: step - 1, error }),
+        }
+    }
+}
+
+impl<Types: StateType> Debug for SagaState<Types> {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SagaState")
+            .field("phase", &self.phase)
+            .field("outcome", &self.outcome)
+            .finish()
+    }
+}
+
+impl<Types: StateType> State<Types> for SagaState<Types> {
+    fn name(&self) -> String {
+        match &self.phase {
+            SagaPhase::Running { step } => self.definition.steps[*step].name.clone(),
+            SagaPhase::Compensating { step, .. } => format!("compensate {}", self.definition.steps[*step].name),
+            SagaPhase::Completed => "Completed".to_string(),
+            SagaPhase::Compensated { .. } => "Compensated".to_string(),
+            SagaPhase::CompensationFailed { .. } => "CompensationFailed".to_string(),
+        }
+    }
+
+    // Step names need not be unique, the phase tells steps apart
+    fn state_key(&self) -> String {
+        format!("{:?}", self.phase)
+    }
+
+    fn initialize(&self) -> Vec<Types::Out> {
+        match &self.phase {
+            SagaPhase::Running { step } => vec![(self.definition.steps[*step].action)()],
+            SagaPhase::Compensating { step, .. } => vec![(self.definition.steps[*step].compensation)()],
+            _ => vec![],
+        }
+    }
+
+    fn deliver(&mut self, message: Types::In) -> DeliveryStatus<Types::In, String> {
+        let awaiting = matches!(self.phase, SagaPhase::Running { .. } | SagaPhase::Compensating { .. });
+        if !awaiting || self.outcome.is_some() {
+            return DeliveryStatus::Unexpected(message);
+        }
+
+        match (self.definition.classifier)(&message) {
+            None => DeliveryStatus::Unexpected(message),
+            Some(event) => {
+                self.outcome = Some(event);
+                DeliveryStatus::Delivered
+            }
+        }
+    }
+
+    fn advance(&self) -> Result<Transition<Types>, String> {
+        let transition = match (&self.phase, &self.outcome) {
+            (SagaPhase::Running { step }, Some(SagaEvent::Completed)) if step + 1 < self.definition.steps.len() => {
+                self.next(SagaPhase::Running { step: step + 1 })
+            }
+            (SagaPhase::Running { .. }, Some(SagaEvent::Completed)) => self.next(SagaPhase::Completed),
+            (SagaPhase::Running { step }, Some(SagaEvent::Failed(error))) => self.compensate_before(*step, error.clone()),
+            (SagaPhase::Running { .. }, None) => Transition::Same,
+            (SagaPhase::Compensating { step, error }, Some(SagaEvent::Completed)) => self.compensate_before(*step, error.clone()),
+            (SagaPhase::Compensating { step, error }, Some(SagaEvent::Failed(compensation_error))) => {
+                self.next(SagaPhase::CompensationFailed { step: *step, error: error.clone(), compensation_error: compensation_error.clone() })
+            }
+            (SagaPhase::Compensating { .. }, None) => Transition::Same,
+            (SagaPhase::Completed, _) | (SagaPhase::Compensated { .. }, _) | (SagaPhase::CompensationFailed { .. }, _) => Transition::Terminal,
+        };
+        Ok(transition)
+    }
+
+    /// Compensate every step whose action may have taken effect, including the running one, last one first.
+    /// The machine no longer steps once cancelled, so the outcome of these compensations is not awaited.
+    fn on_cancel(&self, _reason: &str) -> Vec<Types::Out> {
+        let pending = match (&self.phase, &self.outcome) {
+            // A failed step has nothing to undo
+            (SagaPhase::Running { step }, Some(SagaEvent::Failed(_))) => *step,
+            (SagaPhase::Running { step }, _) => step + 1,
+            // The compensation of the step was already emitted
+            (SagaPhase::Compensating { step, .. }, _) => *step,
+            _ => 0,
+        };
+        self.definition.steps[..pending].iter().rev().map(|step| (step.compensation)()).collect()
+    }
+
+    fn snapshot(&self) -> Option<StateSnapshot> {
+        StateSnapshot::new(SAGA_SNAPSHOT_NAME.to_string(), &SagaSnapshot { phase: self.phase.clone(), outcome: self.outcome.clone() })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::journal::StateSnapshot;
+    use crate::message_channel::create_channel;
+    use crate::saga::{SagaBuilder, SagaEvent, SagaPhase, SagaState};
+    use crate::state::{BoxedState, State, StateMachineMessage, StateType};
+    use crate::state_machine::{StableResult, StateMachine};
+    use crate::state_machine_orchestrator::{SimpleMachineOrchestrator, StateMachineOrchestrator};
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Reply {
+        machine_id: String,
+        ok: bool,
+    }
+
+    impl StateMachineMessage for Reply {
+        fn id(&self) -> &String {
+            &self.machine_id
+        }
+
+        fn unpack(self) -> Self {
+            self
+        }
+    }
+
+    #[derive(Clone, Debug, PartialEq)]
+    struct Command(String);
+
+    impl StateMachineMessage for Command {
+        fn id(&self) -> &String {
+            &self.0
+        }
+
+        fn unpack(self) -> Self {
+            self
+        }
+    }
+
+    struct Types {}
+
+    impl StateType for Types {
+        type In = Reply;
+        type Out = Command;
+
+        fn restore(snapshot: &StateSnapshot) -> Option<BoxedState<Self>> {
+            order_builder().restore(classify, snapshot)
+        }
+    }
+
+    fn order_builder() -> SagaBuilder<Types> {
+        let command = |name: &'static str| move || Command(name.to_string());
+        SagaBuilder::new()
+            .step("reserve", command("reserve"), command("release"))
+            .step("charge", command("charge"), command("refund"))
+            .step("ship", command("ship"), command("recall"))
+    }
+
+    fn classify(reply: &Reply) -> Option<SagaEvent> {
+        Some(if reply.ok { SagaEvent::Completed } else { SagaEvent::Failed("rejected".to_string()) })
+    }
+
+    fn order_saga() -> BoxedState<Types> {
+        order_builder().build(classify).unwrap()
+    }
+
+    fn orchestrator(commands: Rc<RefCell<Vec<String>>>) -> SimpleMachineOrchestrator<Types> {
+        SimpleMachineOrchestrator::new(Box::new(move |command: Command| commands.borrow_mut().push(command.0)))
+    }
+
+    fn phase(orchestrator: &SimpleMachineOrchestrator<Types>, id: &String) -> SagaPhase {
+        orchestrator.get_state_machine(id).unwrap().downcast_state::<SagaState<Types>>().unwrap().phase().clone()
+    }
+
+    #[test]
+    pub fn it_runs_every_step() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let mut orchestrator = orchestrator(commands.clone());
+        let (id, _) = orchestrator.create_machine(order_saga()).unwrap();
+
+        orchestrator.step_until_stable(&id, 10).unwrap();
+        for _ in 0..3 {
+            orchestrator.handle_message(Reply { machine_id: id.clone(), ok: true }).unwrap();
+            orchestrator.step_until_stable(&id, 10).unwrap();
+        }
+
+        assert_eq!(*commands.borrow(), vec!["reserve", "charge", "ship"]);
+        assert_eq!(phase(&orchestrator, &id), SagaPhase::Completed);
+    }
+
+    #[test]
+    pub fn it_compensates_completed_steps_when_a_step_fails() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let mut orchestrator = orchestrator(commands.clone());
+        let (id, _) = orchestrator.create_machine(order_saga()).unwrap();
+        orchestrator.step_until_stable(&id, 10).unwrap();
+
+        for ok in [true, true, false] {
+            orchestrator.handle_message(Reply { machine_id: id.clone(), ok }).unwrap();
+        }
+        orchestrator.step_until_stable(&id, 10).unwrap();
+        assert_eq!(phase(&orchestrator, &id), SagaPhase::Compensating { step: 1, error: "rejected".to_string() });
+
+        // Each compensation waits for its outcome
+        for _ in 0..2 {
+            orchestrator.handle_message(Reply { machine_id: id.clone(), ok: true }).unwrap();
+        }
+        assert_eq!(orchestrator.step_until_stable(&id, 10), Ok(StableResult::Terminated));
+
+        assert_eq!(*commands.borrow(), vec!["reserve", "charge", "ship", "refund", "release"]);
+        assert_eq!(phase(&orchestrator, &id), SagaPhase::Compensated { error: "rejected".to_string() });
+    }
+
+    #[test]
+    pub fn it_stops_when_a_compensation_fails() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let mut orchestrator = orchestrator(commands.clone());
+        let (id, _) = orchestrator.create_machine(order_saga()).unwrap();
+        orchestrator.step_until_stable(&id, 10).unwrap();
+
+        for ok in [true, false, false] {
+            orchestrator.handle_message(Reply { machine_id: id.clone(), ok }).unwrap();
+        }
+        assert_eq!(orchestrator.step_until_stable(&id, 10), Ok(StableResult::Terminated));
+
+        assert_eq!(*commands.borrow(), vec!["reserve", "charge", "release"]);
+        assert_eq!(phase(&orchestrator, &id), SagaPhase::CompensationFailed {
+            step: 0,
+            error: "rejected".to_string(),
+            compensation_error: "rejected".to_string(),
+        });
+    }
+
+    #[test]
+    pub fn it_compensates_completed_steps_when_cancelled() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let mut orchestrator = orchestrator(commands.clone());
+        let (id, _) = orchestrator.create_machine(order_saga()).unwrap();
+        orchestrator.step_until_stable(&id, 10).unwrap();
+        orchestrator.handle_message(Reply { machine_id: id.clone(), ok: true }).unwrap();
+        orchestrator.step_until_stable(&id, 10).unwrap();
+
+        orchestrator.cancel(&id, "customer request").unwrap();
+
+        // The charge is in flight and may go through, so it is refunded too
+        assert_eq!(*commands.borrow(), vec!["reserve", "charge", "refund", "release"]);
+    }
+
+    #[test]
+    pub fn it_replays_from_a_snapshot() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("order".to_string(), sender, order_saga());
+        machine.enable_journal();
+        machine.step().unwrap();
+        handle.send(Reply { machine_id: "order".to_string(), ok: true }).unwrap();
+        machine.step_until_stable(10).unwrap();
+
+        machine.compact_journal().unwrap();
+        assert_eq!(machine.journal().unwrap().snapshot().unwrap().state.name, "SagaState");
+        handle.send(Reply { machine_id: "order".to_string(), ok: false }).unwrap();
+        machine.step().unwrap();
+
+        let (sender, _) = create_channel();
+        let (replayed, _) = StateMachine::replay("order".to_string(), sender, order_saga(), machine.take_journal().unwrap()).unwrap();
+        let state = replayed.downcast_state::<SagaState<Types>>().unwrap();
+        assert_eq!(state.phase(), &SagaPhase::Compensating { step: 0, error: "rejected".to_string() });
+        assert_eq!(state.name(), "compensate reserve");
+    }
+}
@@ -1,15 +1,27 @@
 use std::fmt::Debug;
 
+use candid::CandidType;
+use serde::Deserialize;
 use downcast_rs::{Downcast, impl_downcast};
 
+use crate::journal::StateSnapshot;
+use crate::retry::RetryPolicy;
+use crate::transition_table::StateTransitions;
+
 pub type BoxedState<Types> = Box<dyn State<Types>>;
 
 pub trait StateType: 'static {
     type In: StateMachineMessage;
     type Out: StateMachineMessage;
+
+    /// Rebuild a state from its snapshot when replaying a compacted journal.
+    /// Machines that compact their journal must restore every state that returns a snapshot.
+    fn restore(_snapshot: &StateSnapshot) -> Option<BoxedState<Self>> where Self: Sized {
+        None
+    }
 }
 
-#[derive(Debug, Clone)]
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
 pub struct NoMessage(String);
 
 impl StateMachineMessage for NoMessage {
@@ -25,6 +37,24 @@ impl StateMachineMessage for NoMessage {
 pub trait StateMachineMessage: Debug + Send + Clone {
     fn id(&self) -> &String;
     fn unpack(self) -> Self;
+
+    /// Messages with a higher priority are delivered before queued messages with a lower one.
+    /// Messages of the same priority are delivered in the order they were sent.
+    fn priority(&self) -> u8 {
+        0
+    }
+
+    /// Key identifying retries of the same message. Orchestrators with deduplication enabled
+    /// handle only the first message with a key and answer retries with its outcome.
+    fn idempotency_key(&self) -> Option<String> {
+        None
+    }
+
+    /// Reason to cancel the machine with, making this a cancel message. The next step cancels the
+    /// machine instead of delivering anything, dropping the messages queued with it.
+    fn cancellation(&self) -> Option<String> {
+        None
+    }
 }
 
 // Result from an attempt to deliver a message to a state.
@@ -42,6 +72,17 @@ pub enum Transition<M: StateType> {
 
 pub trait State<Types: StateType>: Downcast + Debug
 {
+    /// Name of the state used in history, defaults to the name of the implementing type
+    fn name(&self) -> String {
+        short_type_name::<Self>()
+    }
+
+    /// Identifies the state when `step_until_stable` looks for transition loops, defaults to its name.
+    /// States that go through the same name with different data, e.g. a countdown, should include the data.
+    fn state_key(&self) -> String {
+        self.name()
+    }
+
     /// Fired once when the state is first entered
     fn initialize(&self) -> Vec<Types::Out> {
         vec![]
@@ -54,6 +95,36 @@ pub trait State<Types: StateType>: Downcast + Debug
 
     /// Called until transition or terminal is returned
     fn advance(&self) -> Result<Transition<Types>, String>;
+
+    /// How the machine retries when `advance` fails in this state. Without a policy errors are only reported.
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        None
+    }
+
+    /// Called when the machine is cancelled while in this state. Returns messages to emit, e.g. to compensate
+    /// for work already done.
+    fn on_cancel(&self, _reason: &str) -> Vec<Types::Out> {
+        vec![]
+    }
+
+    /// Declarative transitions, evaluated in order before `advance`. `advance` is only called when no rule fires.
+    /// Built once when the state becomes current, so guards should read the state rather than capture it.
+    fn transition_table(&self) -> Option<Box<dyn StateTransitions<Types>>> {
+        None
+    }
+
+    /// Encoded copy of the state used to compact a machine's journal, restored by `StateType::restore`.
+    /// States that return None can't be snapshotted.
+    fn snapshot(&self) -> Option<StateSnapshot> {
+        None
+    }
 }
 
 impl_downcast!(State<Types> where Types: StateType);
+
+// Name of the type without its path and generic parameters
+pub(crate) fn short_type_name<T: ?Sized>() -> String {
+    let name = std::any::type_name::<T>();
+    let name = name.split('<').next().unwrap_or(name);
+    name.rsplit("::").next().unwrap_or(name).to_string()
+}
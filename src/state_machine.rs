@@ -1,43 +1,184 @@
-use std::collections::VecDeque;
-use crate::message_channel::{create_channel, MessageReceiver, MessageSender};
-use crate::state::{BoxedState, DeliveryStatus, State, StateType, Transition};
+use std::collections::{HashSet, VecDeque};
+use std::rc::Rc;
+
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::clock::{Clock, SystemClock};
+use crate::history::{AdminAction, TransitionHistory, TransitionRecord};
+use crate::journal::{Journal, JournalEntry, RetryState, Snapshot};
+use crate::logging::{LogLevel, Logger, LogRecord, PrintLogger};
+use crate::message_channel::{create_channel, MessageReceiver, MessageSender, SendError};
+use crate::observer::StateMachineObserver;
+use crate::random::SeededRng;
+use crate::state::{short_type_name, BoxedState, DeliveryStatus, State, StateMachineMessage, StateType, Transition};
+use crate::transition_table::StateTransitions;
 
 pub type StateMachineId = String;
 
 pub struct StateMachineHandle<IncomingMessages : Clone> {
     tx: MessageSender<IncomingMessages>,
+    // Told the machine's id when a message is queued in its empty channel, so an orchestrator knows to step it.
+    // Messages queued behind it are handled by the same step, so they send no more ids.
+    waker: Option<(StateMachineId, MessageSender<StateMachineId>)>,
 }
 
-impl <IncomingMessages : Clone> StateMachineHandle<IncomingMessages> {
-    pub fn clone(&self) -> Self {
+impl <IncomingMessages : Clone> Clone for StateMachineHandle<IncomingMessages> {
+    fn clone(&self) -> Self {
         StateMachineHandle {
             tx: self.tx.clone(),
+            waker: self.waker.clone(),
         }
     }
 }
 
 impl<IncomingMessages : Clone> StateMachineHandle<IncomingMessages> {
-    pub fn send(&self, message: IncomingMessages) -> Result<(), ()> {
-        self.tx.try_send(message)
+    pub fn send(&self, message: IncomingMessages) -> Result<(), SendError<IncomingMessages>> {
+        let (result, filled) = self.tx.try_send_filling(message);
+        if filled {
+            self.wake();
+        }
+        result
+    }
+
+    /// Send several messages with a single access to the machine's channel.
+    /// Messages that did not fit in a bounded channel are returned in the error.
+    pub fn send_batch(&self, messages: Vec<IncomingMessages>) -> Result<(), SendError<Vec<IncomingMessages>>> {
+        let (result, filled) = self.tx.try_send_batch_filling(messages);
+        if filled {
+            self.wake();
+        }
+        result
+    }
+
+    /// Number of messages waiting for the machine to step
+    pub fn pending(&self) -> usize {
+        self.tx.len()
+    }
+
+    /// Maximum number of messages that can wait for the machine, None when unbounded
+    pub fn capacity(&self) -> Option<usize> {
+        self.tx.capacity()
+    }
+
+    /// Whether the machine was dropped. Messages sent to it are returned as `SendError::Disconnected`.
+    pub fn is_closed(&self) -> bool {
+        self.tx.is_closed()
+    }
+
+    pub(crate) fn with_waker(mut self, machine_id: StateMachineId, waker: MessageSender<StateMachineId>) -> Self {
+        self.waker = Some((machine_id, waker));
+        self
+    }
+
+    fn wake(&self) {
+        if let Some((machine_id, waker)) = &self.waker {
+            let _ = waker.try_send(machine_id.clone());
+        }
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum StepResult {
     Running,
     Terminated,
+    /// The machine was cancelled and no longer steps
+    Cancelled,
+    /// The machine is paused, messages sent to it wait until it is resumed
+    Paused,
+    /// The machine ran out of retries and no longer steps
+    Failed,
+    /// The last attempt to advance failed, nothing happens until `next_retry_at`
+    AwaitingRetry,
+}
+
+/// Lifecycle of a machine as a whole, as opposed to the state it is in
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub enum MachineStatus {
+    Running,
+    /// The state returned `Transition::Terminal`
+    Terminated,
+    Cancelled { reason: String },
+    /// Steps are skipped until the machine is resumed
+    Paused,
+    /// `advance` failed with an error the state's retry policy would not retry, or retries ran out
+    Failed { error: String },
+}
+
+/// `MachineStatus` without its details, to filter machines by
+#[derive(CandidType, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusKind {
+    Running,
+    Terminated,
+    Cancelled,
+    Paused,
+    Failed,
+}
+
+impl MachineStatus {
+    pub fn kind(&self) -> StatusKind {
+        match self {
+            MachineStatus::Running => StatusKind::Running,
+            MachineStatus::Terminated => StatusKind::Terminated,
+            MachineStatus::Cancelled { .. } => StatusKind::Cancelled,
+            MachineStatus::Paused => StatusKind::Paused,
+            MachineStatus::Failed { .. } => StatusKind::Failed,
+        }
+    }
+}
+
+/// Why `StateMachine::step_until_stable` returned
+#[derive(Debug, Clone, PartialEq)]
+pub enum StableResult {
+    /// The state returned `Transition::Same`
+    Stable,
+    Terminated,
+    Cancelled,
+    Paused,
+    Failed,
+    AwaitingRetry,
+    /// The machine was still transitioning after the maximum number of steps
+    StepLimitReached,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct StateMachineError {
     message : String,
 }
 
+impl StateMachineError {
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
 pub struct StateMachine<Types: StateType> {
     state_machine_id: String,
     state: BoxedState<Types>,
+    // Transition table of the current state, built once when the state became current
+    transitions: Option<Box<dyn StateTransitions<Types>>>,
     message_queue: VecDeque<Types::In>,
     is_state_initialized: bool,
+    steps: u64,
+    status: MachineStatus,
+    // Clock time the machine was created at, as set by its orchestrator
+    created_at: u64,
+    // Failed attempts to advance the current state, and when to try again
+    attempts: u32,
+    next_retry_at: Option<u64>,
+    // Draws retry jitter
+    rng: SeededRng,
+    clock: Rc<dyn Clock>,
+    logger: Rc<dyn Logger>,
+
+    // Bounded transition log, only kept when enabled
+    history: Option<TransitionHistory<Types::In, Types::Out>>,
+    // Messages delivered to and emitted by the current state, kept for the next history record
+    delivered_messages: Vec<Types::In>,
+    emitted_messages: Vec<Types::Out>,
+    // Log of delivered messages the machine can be rebuilt from, only kept when enabled
+    journal: Option<Journal<Types>>,
+    observers: Vec<Rc<dyn StateMachineObserver<Types>>>,
 
     // Receives messages for states
     inbound_message_channel: MessageReceiver<Types::In>,
@@ -51,23 +192,49 @@ impl<Types> StateMachine<Types>
     /// Create a new state machine with the given initial state.
     /// Return a StateMachine and StateMachineHandle that can be used to send messages to the state machine.
     pub fn new(state_machine_id: String, outbound_message_channel: MessageSender<Types::Out>, state: Box<dyn State<Types>>) -> (StateMachine<Types>, StateMachineHandle<Types::In>) {
-        let (tx, inbound_message_channel) = create_channel::<Types::In>();
+        Self::with_inbound_channel(state_machine_id, outbound_message_channel, state, create_channel::<Types::In>())
+    }
+
+    /// Create a new state machine that receives messages through the given channel, e.g. a bounded one.
+    pub fn with_inbound_channel(state_machine_id: String, outbound_message_channel: MessageSender<Types::Out>, state: Box<dyn State<Types>>, inbound_channel: (MessageSender<Types::In>, MessageReceiver<Types::In>)) -> (StateMachine<Types>, StateMachineHandle<Types::In>) {
+        let (tx, inbound_message_channel) = inbound_channel;
+        let rng = SeededRng::from_bytes(state_machine_id.as_bytes());
+        let transitions = state.transition_table();
 
         (
             StateMachine {
                 state_machine_id,
                 state,
+                transitions,
                 message_queue: VecDeque::new(),
                 is_state_initialized: false,
+                steps: 0,
+                status: MachineStatus::Running,
+                created_at: 0,
+                attempts: 0,
+                next_retry_at: None,
+                rng,
+                clock: Rc::new(SystemClock),
+                logger: Rc::new(PrintLogger::default()),
+                history: None,
+                delivered_messages: vec![],
+                emitted_messages: vec![],
+                journal: None,
+                observers: vec![],
                 inbound_message_channel,
                 outbound_message_channel,
             },
             StateMachineHandle {
-                tx
+                tx,
+                waker: None,
             }
         )
     }
 
+    pub fn id(&self) -> &StateMachineId {
+        &self.state_machine_id
+    }
+
     /// Return the current state of the machine
     pub fn state(&self) -> &dyn State<Types> {
         &*self.state
@@ -80,58 +247,664 @@ impl<Types> StateMachine<Types>
         self.state.downcast_ref::<T>()
     }
 
+    /// Whether the current state was initialized, which is false until the first step after a transition
+    pub fn is_state_initialized(&self) -> bool {
+        self.is_state_initialized
+    }
+
+    pub fn status(&self) -> &MachineStatus {
+        &self.status
+    }
+
+    /// When the orchestrator created the machine, in nanoseconds. Zero for machines created on their own.
+    pub fn created_at(&self) -> u64 {
+        self.created_at
+    }
+
+    pub(crate) fn set_created_at(&mut self, created_at: u64) {
+        self.created_at = created_at;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.set_created_at(created_at);
+        }
+    }
+
+    /// Consecutive failed attempts to advance the current state
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// When the failed state should be stepped again, following its retry policy
+    pub fn next_retry_at(&self) -> Option<u64> {
+        self.next_retry_at
+    }
+
+    /// Replace the random number generator used for retry jitter, which is seeded from the machine id by default
+    pub fn set_rng(&mut self, rng: SeededRng) {
+        self.rng = rng;
+    }
+
+    /// Number of times the machine has been stepped
+    pub fn steps(&self) -> u64 {
+        self.steps
+    }
+
+    /// Replace the clock used to timestamp history and log records
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Replace the logger, which defaults to a `PrintLogger`
+    pub fn set_logger(&mut self, logger: Rc<dyn Logger>) {
+        self.logger = logger;
+    }
+
+    /// Start recording transitions, keeping at most `capacity` of the most recent ones.
+    /// Replaces any history recorded so far.
+    pub fn enable_history(&mut self, capacity: usize) {
+        self.history = Some(TransitionHistory::new(capacity));
+    }
+
+    /// Recorded transitions, if history is enabled
+    pub fn history(&self) -> Option<&TransitionHistory<Types::In, Types::Out>> {
+        self.history.as_ref()
+    }
+
+    /// Register an observer to be notified of lifecycle events of this machine
+    pub fn add_observer(&mut self, observer: Rc<dyn StateMachineObserver<Types>>) {
+        self.observers.push(observer);
+    }
+
+    /// Start journaling delivered messages so the machine can be rebuilt with `replay`.
+    /// Replaces any journal kept so far.
+    pub fn enable_journal(&mut self) {
+        let mut journal = Journal::new();
+        journal.set_created_at(self.created_at);
+        self.journal = Some(journal);
+    }
+
+    /// The journal of delivered messages, if journaling is enabled
+    pub fn journal(&self) -> Option<&Journal<Types>> {
+        self.journal.as_ref()
+    }
+
+    /// Remove the journal from the machine, e.g. to persist it before an upgrade. Journaling stops.
+    pub fn take_journal(&mut self) -> Option<Journal<Types>> {
+        self.journal.take()
+    }
+
+    /// Replace the journal entries with a snapshot of the current state.
+    /// Fails when journaling is disabled or the current state does not support snapshots.
+    pub fn compact_journal(&mut self) -> Result<(), StateMachineError> {
+        let retry = self.retry_state();
+        let journal = self.journal.as_mut().ok_or_else(|| StateMachineError {
+            message: "Journal is not enabled".to_string(),
+        })?;
+        let state = self.state.snapshot().ok_or_else(|| StateMachineError {
+            message: format!("State {} does not support snapshots", self.state.name()),
+        })?;
+
+        journal.compact(Snapshot {
+            state,
+            is_state_initialized: self.is_state_initialized,
+            step: self.steps,
+            status: self.status.clone(),
+            retry,
+        });
+        Ok(())
+    }
+
+    /// Rebuild a state machine by delivering the journaled messages to the initial state, or to the
+    /// journal's snapshot if it has one. Messages emitted by states during the replay are discarded
+    /// as they were already sent by the original machine. The returned machine keeps journaling.
+    /// `initial` is ignored when the journal has a snapshot, which is restored with `StateType::restore`.
+    pub fn replay(state_machine_id: String, outbound_message_channel: MessageSender<Types::Out>, initial: Box<dyn State<Types>>, journal: Journal<Types>) -> Result<(StateMachine<Types>, StateMachineHandle<Types::In>), StateMachineError> {
+        let (mut machine, handle) = StateMachine::new(state_machine_id, outbound_message_channel, initial);
+        machine.created_at = journal.created_at();
+
+        if let Some(snapshot) = journal.snapshot() {
+            let state = Types::restore(&snapshot.state).ok_or_else(|| StateMachineError {
+                message: format!("State {} could not be restored from its snapshot", snapshot.state.name),
+            })?;
+            machine.enter(state);
+            machine.is_state_initialized = snapshot.is_state_initialized;
+            machine.steps = snapshot.step;
+            machine.status = snapshot.status.clone();
+            machine.restore_retry_state(&snapshot.retry)?;
+        }
+
+        for entry in journal.entries() {
+            machine.replay_entry(entry)?;
+        }
+
+        machine.journal = Some(journal);
+        Ok((machine, handle))
+    }
+
+    fn replay_entry(&mut self, entry: &JournalEntry<Types::In>) -> Result<(), StateMachineError> {
+        self.steps = entry.step;
+
+        if let Some(status) = &entry.status {
+            self.status = status.clone();
+            return Ok(());
+        }
+
+        if !self.is_state_initialized {
+            self.state.initialize();
+            self.is_state_initialized = true;
+        }
+
+        let last = entry.messages.len().saturating_sub(1);
+        for (index, message) in entry.messages.iter().cloned().enumerate() {
+            match self.state.deliver(message) {
+                DeliveryStatus::Delivered => {}
+                // The step stopped at this message too
+                DeliveryStatus::Error(_) if index == last && entry.delivery_error.is_some() => {}
+                DeliveryStatus::Unexpected(message) => {
+                    return Err(StateMachineError {
+                        message: format!("Unexpected message during replay of step {}: {:?}", entry.step, message),
+                    })
+                }
+                DeliveryStatus::Error(error) => {
+                    return Err(StateMachineError {
+                        message: format!("Error during replay of step {}: {}", entry.step, error),
+                    })
+                }
+            }
+        }
+
+        if entry.advanced {
+            match self.next_transition() {
+                Ok((Transition::Next(state), _)) => self.enter(state),
+                Ok((Transition::Terminal, _)) => self.status = MachineStatus::Terminated,
+                _ => {}
+            }
+        }
+        if let Some(retry) = &entry.retry {
+            self.restore_retry_state(retry)?;
+        }
+
+        Ok(())
+    }
+
     /// Drive the state machine forward by processing a messages in the queue and advancing the state.
+    /// When `advance` fails and the state's retry policy schedules another attempt, observers are told about the error
+    /// and the step returns `StepResult::AwaitingRetry`. Errors are returned once the machine fails.
     pub fn step(&mut self) -> Result<StepResult, StateMachineError> {
+        match self.status {
+            MachineStatus::Terminated => return Ok(StepResult::Terminated),
+            MachineStatus::Cancelled { .. } => return Ok(StepResult::Cancelled),
+            MachineStatus::Paused => return Ok(StepResult::Paused),
+            MachineStatus::Failed { .. } => return Ok(StepResult::Failed),
+            MachineStatus::Running => {}
+        }
+
+        // Drain message channel
+        while let Ok(message) = self.inbound_message_channel.try_receive() {
+            self.enqueue(message);
+        }
+
+        // Cancel messages take effect before anything queued is delivered
+        if let Some(reason) = self.message_queue.iter().find_map(|message| message.cancellation()) {
+            self.message_queue.clear();
+            self.cancel(&reason)?;
+            return Ok(StepResult::Cancelled);
+        }
+
+        // Messages stay queued until the failed state is due another attempt
+        if self.next_retry_at.is_some_and(|at| at > self.clock.now()) {
+            return Ok(StepResult::AwaitingRetry);
+        }
+        self.steps += 1;
+
         // If the current state is not initialized do that first
-        if !self.is_state_initialized {
-            let messages = self.state.initialize();
-            messages.into_iter().for_each(|message| self.outbound_message_channel.try_send(message).unwrap());
+        let initializing = !self.is_state_initialized;
+        if initializing {
+            self.initialize_state();
+        }
 
-            self.is_state_initialized = true;
+        let mut delivered = vec![];
+        let mut rejected = None;
+        let delivery = self.deliver_queued_messages(&mut delivered, &mut rejected);
+        if self.history.is_some() {
+            self.delivered_messages.extend(delivered.iter().cloned());
         }
 
-        // Drain message channel
-        loop {
-            match self.inbound_message_channel.try_receive() {
+        // Attempt to advance the state machine
+        let advanced = delivery.is_ok();
+        let result = delivery.and_then(|_| self.advance_state());
+        if let Err(error) = &result {
+            self.notify(|observer| observer.on_step_error(&self.state_machine_id, error));
+        }
+
+        // Apply the retry policy before journaling, so the entry has the retry state the step left
+        let retrying = self.attempts > 0;
+        let mut retry_scheduled = false;
+        let failed = match &result {
+            Err(error) if advanced => {
+                let failed = self.schedule_retry(error);
+                retry_scheduled = !failed && self.next_retry_at.is_some();
+                failed
+            }
+            Ok(_) => {
+                self.attempts = 0;
+                self.next_retry_at = None;
+                false
+            }
+            Err(_) => false,
+        };
+        let retry = (retrying || self.attempts > 0).then(|| self.retry_state());
+
+        if let Some(journal) = self.journal.as_mut() {
+            let transitioned = !self.is_state_initialized;
+            // The state may have changed before erroring, so replays deliver the message too
+            let delivery_error = match (rejected, &result) {
+                (Some(message), Err(error)) => {
+                    delivered.push(message);
+                    Some(error.message.clone())
+                }
+                _ => None,
+            };
+            if initializing || transitioned || !delivered.is_empty() || retry.is_some() {
+                journal.append(JournalEntry {
+                    step: self.steps,
+                    messages: delivered,
+                    advanced,
+                    status: None,
+                    retry,
+                    delivery_error,
+                });
+            }
+        }
+
+        if let (true, Err(error)) = (failed, &result) {
+            self.set_status(MachineStatus::Failed { error: error.message.clone() });
+        }
+        if retry_scheduled {
+            return Ok(StepResult::AwaitingRetry);
+        }
+        result
+    }
+
+    // Apply the state's retry policy to a failed `advance`, returning whether the machine fails as it won't be retried
+    fn schedule_retry(&mut self, error: &StateMachineError) -> bool {
+        let Some(policy) = self.state.retry_policy() else {
+            return false;
+        };
+
+        self.attempts += 1;
+        if policy.is_retryable(&error.message) && self.attempts < policy.max_attempts() {
+            let backoff = policy.backoff(self.attempts, &mut self.rng);
+            self.next_retry_at = Some(self.clock.now().saturating_add(backoff));
+            self.log(LogLevel::Warn, None, || format!("Attempt {} failed, retrying in {}ns: {}", self.attempts, backoff, error.message));
+            false
+        } else {
+            self.next_retry_at = None;
+            self.log(LogLevel::Error, None, || format!("Failed after {} attempts: {}", self.attempts, error.message));
+            true
+        }
+    }
+
+    fn retry_state(&self) -> RetryState {
+        RetryState {
+            attempts: self.attempts,
+            next_retry_at: self.next_retry_at,
+            rng: self.rng.state(),
+        }
+    }
+
+    fn restore_retry_state(&mut self, retry: &RetryState) -> Result<(), StateMachineError> {
+        self.rng = SeededRng::from_state(&retry.rng).ok_or_else(|| StateMachineError {
+            message: "Invalid random number generator state".to_string(),
+        })?;
+        self.attempts = retry.attempts;
+        self.next_retry_at = retry.next_retry_at;
+        Ok(())
+    }
 
-                Ok(Some(message)) => {
-                    self.message_queue.push_back(message);
-                },
-                _ => break
+    /// Step until the state stops transitioning, the machine terminates or `max_steps` steps were taken.
+    /// Fails when a step fails, or when the machine returns to a state it was in during this call,
+    /// including the one it started in, judged by `State::state_key`, as it would keep transitioning in a loop.
+    pub fn step_until_stable(&mut self, max_steps: u64) -> Result<StableResult, StateMachineError> {
+        let mut visited = HashSet::from([self.state.state_key()]);
+
+        for _ in 0..max_steps {
+            match self.step()? {
+                StepResult::Terminated => return Ok(StableResult::Terminated),
+                StepResult::Cancelled => return Ok(StableResult::Cancelled),
+                StepResult::Paused => return Ok(StableResult::Paused),
+                StepResult::Failed => return Ok(StableResult::Failed),
+                StepResult::AwaitingRetry => return Ok(StableResult::AwaitingRetry),
+                StepResult::Running => {}
+            }
+            if self.is_state_initialized {
+                return Ok(StableResult::Stable);
+            }
+
+            if !visited.insert(self.state.state_key()) {
+                return Err(StateMachineError {
+                    message: format!("Transition loop detected, returned to state {}", self.state.name()),
+                });
             }
         }
 
+        Ok(StableResult::StepLimitReached)
+    }
+
+    /// Stop the machine: the state's `on_cancel` hook runs and the messages it returns are emitted,
+    /// then the machine no longer steps. Fails unless the machine is running or paused.
+    pub fn cancel(&mut self, reason: &str) -> Result<(), StateMachineError> {
+        if !matches!(self.status, MachineStatus::Running | MachineStatus::Paused) {
+            return Err(StateMachineError {
+                message: format!("Machine is not running: {:?}", self.status),
+            });
+        }
+
+        let compensations = self.state.on_cancel(reason);
+        self.emit(compensations);
+        let messages = std::mem::take(&mut self.delivered_messages);
+        let emitted = std::mem::take(&mut self.emitted_messages);
+        self.record(None, messages, emitted, None, Some(reason.to_string()));
+        self.set_status(MachineStatus::Cancelled { reason: reason.to_string() });
+        self.notify(|observer| observer.on_cancelled(&self.state_machine_id, &*self.state, reason));
+        self.log(LogLevel::Info, None, || format!("Cancelled: {}", reason));
+        Ok(())
+    }
+
+    /// Skip steps until `resume` is called. Messages sent meanwhile stay in the inbound channel.
+    /// Fails unless the machine is running.
+    pub fn pause(&mut self) -> Result<(), StateMachineError> {
+        if self.status != MachineStatus::Running {
+            return Err(StateMachineError {
+                message: format!("Machine is not running: {:?}", self.status),
+            });
+        }
+
+        self.set_status(MachineStatus::Paused);
+        self.log(LogLevel::Info, None, || "Paused".to_string());
+        Ok(())
+    }
+
+    /// Step again after `pause`. Fails unless the machine is paused.
+    pub fn resume(&mut self) -> Result<(), StateMachineError> {
+        if self.status != MachineStatus::Paused {
+            return Err(StateMachineError {
+                message: format!("Machine is not paused: {:?}", self.status),
+            });
+        }
+
+        self.set_status(MachineStatus::Running);
+        self.log(LogLevel::Info, None, || "Resumed".to_string());
+        Ok(())
+    }
+
+    /// Replace the current state without calling `advance`, e.g. to repair a machine stuck after a bug.
+    /// A terminated, cancelled or failed machine runs again. The new state is initialized by the next step.
+    /// The journal can't replay the transition, so it is compacted into a snapshot of the new state.
+    /// Fails without changing anything when journaling is enabled and the new state does not support snapshots.
+    pub fn force_transition(&mut self, state: BoxedState<Types>, reason: &str) -> Result<(), StateMachineError> {
+        if self.journal.is_some() && state.snapshot().is_none() {
+            return Err(StateMachineError {
+                message: format!("State {} does not support snapshots, the journal can't record the transition", state.name()),
+            });
+        }
+
+        let messages = std::mem::take(&mut self.delivered_messages);
+        let emitted = std::mem::take(&mut self.emitted_messages);
+        let to = state.name();
+        self.record(Some(to.clone()), messages, emitted, Some(AdminAction::ForceTransition { reason: reason.to_string() }), None);
+        self.log(LogLevel::Warn, None, || format!("Forced transition to {}: {}", to, reason));
+
+        self.notify(|observer| observer.on_state_exited(&self.state_machine_id, &*self.state));
+        self.enter(state);
+        self.attempts = 0;
+        self.next_retry_at = None;
+        if !matches!(self.status, MachineStatus::Running | MachineStatus::Paused) {
+            self.set_status(MachineStatus::Running);
+        }
+        self.notify_admin_action(AdminAction::ForceTransition { reason: reason.to_string() });
+        self.compact_after_admin_action()
+    }
+
+    /// Deliver the message to the current state right away, ahead of queued messages.
+    /// The state advances on the next step. Fails unless the machine is running or paused,
+    /// as stopped machines would never handle it; revive them with `force_transition` first.
+    pub fn inject_message(&mut self, message: Types::In, reason: &str) -> Result<(), StateMachineError> {
+        if !matches!(self.status, MachineStatus::Running | MachineStatus::Paused) {
+            return Err(StateMachineError {
+                message: format!("Machine is not running: {:?}", self.status),
+            });
+        }
+
+        let message_id = message.id().clone();
+        let copy = message.clone();
+
+        match self.state.deliver(message) {
+            DeliveryStatus::Delivered => {}
+            DeliveryStatus::Unexpected(message) => {
+                return Err(StateMachineError { message: format!("Unexpected message: {:?}", message) });
+            }
+            DeliveryStatus::Error(error) => {
+                return Err(StateMachineError { message: error });
+            }
+        }
+
+        self.notify(|observer| observer.on_message_delivered(&self.state_machine_id, &copy));
+        self.log(LogLevel::Warn, Some(message_id), || format!("Injected message: {}", reason));
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append(JournalEntry {
+                step: self.steps,
+                messages: vec![copy.clone()],
+                advanced: false,
+                status: None,
+                retry: None,
+                delivery_error: None,
+            });
+        }
+        self.record(Some(self.state.name()), vec![copy], vec![], Some(AdminAction::InjectMessage { reason: reason.to_string() }), None);
+        self.notify_admin_action(AdminAction::InjectMessage { reason: reason.to_string() });
+        Ok(())
+    }
+
+    /// Edit the current state in place, failing when it is not a `T`.
+    /// The journal can't replay the edit, so it is compacted into a snapshot of the edited state.
+    /// Fails without editing when journaling is enabled and the state does not support snapshots.
+    pub fn edit_state<T>(&mut self, reason: &str, edit: impl FnOnce(&mut T)) -> Result<(), StateMachineError>
+        where T: State<Types>
+    {
+        let name = self.state.name();
+        if self.journal.is_some() && self.state.snapshot().is_none() {
+            return Err(StateMachineError {
+                message: format!("State {} does not support snapshots, the journal can't record the edit", name),
+            });
+        }
+        let state = self.state.downcast_mut::<T>().ok_or_else(|| StateMachineError {
+            message: format!("Current state {} is not a {}", name, short_type_name::<T>()),
+        })?;
+        edit(state);
+
+        self.log(LogLevel::Warn, None, || format!("Edited state: {}", reason));
+        self.record(Some(name), vec![], vec![], Some(AdminAction::EditState { reason: reason.to_string() }), None);
+        self.notify_admin_action(AdminAction::EditState { reason: reason.to_string() });
+        self.compact_after_admin_action()
+    }
+
+    fn notify_admin_action(&self, action: AdminAction) {
+        self.notify(|observer| observer.on_admin_action(&self.state_machine_id, &*self.state, &action));
+    }
+
+    // Start the journal over from the repaired state, which replaying the entries would not reach
+    fn compact_after_admin_action(&mut self) -> Result<(), StateMachineError> {
+        match self.journal {
+            Some(_) => self.compact_journal(),
+            None => Ok(()),
+        }
+    }
+
+    // Change the status outside of a step, journaling the change so replays restore it
+    fn set_status(&mut self, status: MachineStatus) {
+        self.status = status;
+        if let Some(journal) = self.journal.as_mut() {
+            journal.append(JournalEntry {
+                step: self.steps,
+                messages: vec![],
+                advanced: false,
+                status: Some(self.status.clone()),
+                retry: None,
+                delivery_error: None,
+            });
+        }
+    }
+
+    // Queue the message behind every message of the same or a higher priority
+    fn enqueue(&mut self, message: Types::In) {
+        let priority = message.priority();
+        let position = self.message_queue
+            .iter()
+            .rposition(|queued| queued.priority() >= priority)
+            .map_or(0, |index| index + 1);
+        self.message_queue.insert(position, message);
+    }
+
+    // Make the state current, to be initialized by the next step
+    fn enter(&mut self, state: BoxedState<Types>) {
+        self.transitions = state.transition_table();
+        self.state = state;
+        self.is_state_initialized = false;
+    }
+
+    fn initialize_state(&mut self) {
+        self.notify(|observer| observer.on_state_entered(&self.state_machine_id, &*self.state));
+
+        let messages = self.state.initialize();
+        self.emit(messages);
+
+        self.is_state_initialized = true;
+    }
+
+    fn emit(&mut self, messages: Vec<Types::Out>) {
+        for message in messages {
+            self.notify(|observer| observer.on_outbound_emitted(&self.state_machine_id, &message));
+            if self.history.is_some() {
+                self.emitted_messages.push(message.clone());
+            }
+            if let Err(error) = self.outbound_message_channel.try_send(message) {
+                self.log(LogLevel::Error, None, || format!("Failed to send outbound message: {:?}", error));
+            }
+        }
+    }
+
+    // The first rule of the state's transition table that fires, or else the result of `advance`
+    fn next_transition(&self) -> Result<(Transition<Types>, Vec<Types::Out>), String> {
+        if let Some(fired) = self.transitions.as_ref().and_then(|table| table.fire(&*self.state)) {
+            return Ok(fired);
+        }
+        self.state.advance().map(|transition| (transition, vec![]))
+    }
+
+    // Deliver queued messages until one fails, collecting delivered messages when they are recorded,
+    // and the message the state consumed but answered with an error
+    fn deliver_queued_messages(&mut self, delivered: &mut Vec<Types::In>, rejected: &mut Option<Types::In>) -> Result<(), StateMachineError> {
+        let recording = self.history.is_some() || self.journal.is_some();
+
         while let Some(message) = self.message_queue.pop_front() {
+            let copy = if recording || !self.observers.is_empty() { Some(message.clone()) } else { None };
+
             match self.state.deliver(message) {
-                DeliveryStatus::Delivered => {}
+                DeliveryStatus::Delivered => {
+                    if let Some(message) = copy {
+                        self.notify(|observer| observer.on_message_delivered(&self.state_machine_id, &message));
+                        if recording {
+                            delivered.push(message);
+                        }
+                    }
+                }
                 DeliveryStatus::Unexpected(message) => {
-                    println!("Unexpected message: {:?}", message);
-                    return Err(StateMachineError {
+                    self.log(LogLevel::Warn, Some(message.id().clone()), || format!("Unexpected message: {:?}", message));
+                    let error = StateMachineError {
                         message: format!("Unexpected message: {:?}", message),
-                    })
+                    };
+                    self.notify(|observer| observer.on_message_rejected(&self.state_machine_id, &message, &error));
+                    return Err(error)
                 }
                 DeliveryStatus::Error(error) => {
-                    print!("Error: {}", error);
-                    return Err(StateMachineError{ message: error })
+                    // The state consumed the message, its id is only known when a copy was kept
+                    let message_id = copy.as_ref().map(|message| message.id().clone());
+                    self.log(LogLevel::Error, message_id, || format!("Error: {}", error));
+                    let error = StateMachineError{ message: error };
+                    if let Some(message) = copy {
+                        self.notify(|observer| observer.on_message_rejected(&self.state_machine_id, &message, &error));
+                        if recording {
+                            *rejected = Some(message);
+                        }
+                    }
+                    return Err(error)
                 }
             }
         }
 
-        // Attempt to advance the state machine
-        let advanced = self.state.advance().map_err(|e| StateMachineError { message: e })?;
+        Ok(())
+    }
 
-        return match advanced {
+    fn advance_state(&mut self) -> Result<StepResult, StateMachineError> {
+        let (advanced, emitted) = self.next_transition().map_err(|e| StateMachineError { message: e })?;
+        self.emit(emitted);
+
+        match advanced {
             Transition::Same => {
                 Ok(StepResult::Running)
             }
             Transition::Next(state) => {
-                self.state = state;
-                self.is_state_initialized = false;
-                return Ok(StepResult::Running)
+                self.record_transition(Some(state.name()));
+                self.notify(|observer| observer.on_state_exited(&self.state_machine_id, &*self.state));
+                self.enter(state);
+                Ok(StepResult::Running)
             }
             Transition::Terminal => {
-                return Ok(StepResult::Terminated)
+                self.record_transition(None);
+                self.status = MachineStatus::Terminated;
+                self.notify(|observer| observer.on_terminated(&self.state_machine_id, &*self.state));
+                Ok(StepResult::Terminated)
             }
         }
     }
-}
\ No newline at end of file
+
+    fn record_transition(&mut self, to: Option<String>) {
+        let messages = std::mem::take(&mut self.delivered_messages);
+        let emitted = std::mem::take(&mut self.emitted_messages);
+        self.record(to, messages, emitted, None, None);
+    }
+
+    fn record(&mut self, to: Option<String>, messages: Vec<Types::In>, emitted: Vec<Types::Out>, admin_action: Option<AdminAction>, cancel_reason: Option<String>) {
+        if let Some(history) = self.history.as_mut() {
+            history.record(TransitionRecord {
+                step: self.steps,
+                timestamp: self.clock.now(),
+                from: self.state.name(),
+                to,
+                messages,
+                emitted,
+                admin_action,
+                cancel_reason,
+            });
+        }
+    }
+
+    fn log(&self, level: LogLevel, message_id: Option<String>, text: impl FnOnce() -> String) {
+        if !self.logger.enabled(level) {
+            return;
+        }
+
+        self.logger.log(LogRecord {
+            level,
+            timestamp: self.clock.now(),
+            machine_id: Some(self.state_machine_id.clone()),
+            state_name: Some(self.state.name()),
+            message_id,
+            message: text(),
+        });
+    }
+
+    fn notify(&self, event: impl Fn(&dyn StateMachineObserver<Types>)) {
+        self.observers.iter().for_each(|observer| event(&**observer));
+    }
+}
@@ -1,82 +1,383 @@
-use std::collections::{HashMap, VecDeque};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
+use std::ops::Bound;
+use std::rc::Rc;
 
-use crate::message_channel::{create_channel, MessageReceiver};
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::clock::{Clock, SystemClock};
+use crate::id_generator::{IdGenerator, IdGeneratorState, MonotonicIdGenerator};
+use crate::idempotency::IdempotencyCache;
+use crate::instructions::{IcInstructionCounter, InstructionCounter};
+use crate::logging::{LogLevel, Logger, LogRecord, PrintLogger};
+use crate::message_channel::{create_bounded_channel, create_channel, MessageReceiver, MessageSender, OverflowPolicy, SendError};
+use crate::metrics::OrchestratorMetrics;
+use crate::observer::StateMachineObserver;
 use crate::state::{State, StateMachineMessage, StateType};
-use crate::state_machine::{StateMachine, StateMachineHandle, StateMachineId};
+use crate::state_machine::{MachineStatus, StableResult, StateMachine, StateMachineError, StateMachineHandle, StateMachineId, StatusKind, StepResult};
 
 pub trait StateMachineOrchestrator<Types: StateType> {
-    fn create_machine(&mut self, state: Box<dyn State<Types>>) -> (StateMachineId, StateMachineHandle<Types::In>);
-    fn handle_message(&mut self, message: Types::In) -> ();
-    fn step_machine(&mut self, machine_id: &String) -> ();
+    /// Create a machine with a generated id. Fails when the id generator keeps producing ids already in use.
+    fn create_machine(&mut self, state: Box<dyn State<Types>>) -> Result<(StateMachineId, StateMachineHandle<Types::In>), OrchestratorError>;
+    fn handle_message(&mut self, message: Types::In) -> Result<StepResult, OrchestratorError>;
+    /// Route many messages, stepping each machine that received any of them once.
+    /// Returns a result per machine, and the messages that could not be queued so they can be sent again.
+    fn handle_messages(&mut self, messages: Vec<Types::In>) -> BatchReport<Types::In>;
+    fn step_machine(&mut self, machine_id: &str) -> Result<StepResult, OrchestratorError>;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum OrchestratorError {
+    /// No machine has the id
+    MachineNotFound(StateMachineId),
+    /// The machine's inbound channel is full
+    QueueFull(StateMachineId),
+    /// The machine's inbound channel is in use
+    QueueBusy(StateMachineId),
+    /// The machine's inbound channel was closed
+    MachineClosed(StateMachineId),
+    /// The machine failed to step
+    StepFailed(StateMachineId, StateMachineError),
+    /// The machine's `StateType` has no conversion for the message
+    MessageNotAccepted(StateMachineId),
+    /// The machine was terminated, cancelled, failed or paused
+    NotRunning(StateMachineId, MachineStatus),
+    /// The machine can't be resumed as it is not paused
+    NotPaused(StateMachineId, MachineStatus),
+    /// A machine with the id already exists
+    DuplicateId(StateMachineId),
+    /// No message adapter was registered for the `StateType` with the name
+    TypeNotRegistered(String),
+    /// The id generator produced `MAX_ID_ATTEMPTS` ids in a row that were already in use
+    IdGeneratorExhausted,
+    /// The current state of the machine, named in the error, is not the type the repair expected
+    StateMismatch(StateMachineId, String),
+    /// The machine could not be repaired, see `force_transition` and `edit_state`
+    AdminActionFailed(StateMachineId, StateMachineError),
+}
+
+/// Outcome of delivering a message to several machines
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct DeliveryReport {
+    /// Machines that stepped successfully after receiving the message
+    pub accepted: Vec<StateMachineId>,
+    /// Machines that could not queue or handle the message
+    pub rejected: Vec<(StateMachineId, OrchestratorError)>,
+}
+
+/// Outcome of `handle_messages`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BatchReport<In> {
+    /// Result per machine, in the order the machines first appear in the batch
+    pub results: Vec<(StateMachineId, Result<StepResult, OrchestratorError>)>,
+    /// Messages left out because their machine's channel was full or busy, in the order they were sent
+    pub unsent: Vec<In>,
+    /// Messages left out as retries of an earlier message with their idempotency key, in the order they were sent.
+    /// Each is answered with the machine, the key and the outcome of the first message with the key,
+    /// whether that message was handled before or earlier in the batch.
+    pub duplicates: Vec<(StateMachineId, String, Result<StepResult, OrchestratorError>)>,
+}
+
+/// Outcome of `step_machines_within`
+#[derive(Debug, Clone, PartialEq)]
+pub struct BudgetedStepReport {
+    /// Number of machines stepped
+    pub stepped: usize,
+    /// Whether stepping stopped before every machine was stepped once.
+    /// The next call continues with the machine after the last one stepped.
+    pub budget_exhausted: bool,
 }
 
+/// Which machines `list_machines` returns. Filters that are not set match every machine.
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq, Default)]
+pub struct MachineQuery {
+    /// Name of the current state, as returned by `State::name`
+    pub state_name: Option<String>,
+    pub status: Option<StatusKind>,
+    /// Only machines created at or after the time, in nanoseconds
+    pub created_after: Option<u64>,
+    /// Only machines created before the time, in nanoseconds
+    pub created_before: Option<u64>,
+    /// Only machines with every one of the tags
+    pub tags: Vec<String>,
+    /// Continue after the machine with the id, the `next` of the previous page
+    pub after: Option<StateMachineId>,
+    /// Maximum number of machines in the page, `DEFAULT_PAGE_LIMIT` when not set.
+    /// Clamped to between 1 and `MAX_PAGE_LIMIT`.
+    pub limit: Option<usize>,
+}
+
+/// Machines listed by `list_machines`, ordered by id
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct MachinePage {
+    pub machines: Vec<MachineSummary>,
+    /// Where the next page starts, None when no machines are left to scan.
+    /// Set on a short or empty page too when `MAX_PAGE_SCAN` machines were scanned without filling it.
+    pub next: Option<StateMachineId>,
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone, PartialEq)]
+pub struct MachineSummary {
+    pub id: StateMachineId,
+    pub state_name: String,
+    pub status: MachineStatus,
+    pub created_at: u64,
+    pub tags: Vec<String>,
+    pub steps: u64,
+}
+
+impl OrchestratorError {
+    pub(crate) fn from_send_error<T>(machine_id: &StateMachineId, error: &SendError<T>) -> Self {
+        match error {
+            SendError::Full(_) => OrchestratorError::QueueFull(machine_id.clone()),
+            SendError::Busy(_) => OrchestratorError::QueueBusy(machine_id.clone()),
+            SendError::Disconnected(_) => OrchestratorError::MachineClosed(machine_id.clone()),
+        }
+    }
+}
+
+/// Decides whether an outbound message is addressed to another machine of the orchestrator.
+/// Returns the inbound message to deliver, routed by its id, or gives the message back for the command handler.
+pub type MessageRouter<Types> = Box<dyn Fn(<Types as StateType>::Out) -> Result<<Types as StateType>::In, <Types as StateType>::Out>>;
+
+/// Routed messages delivered per round before the rest is queued, see `set_max_hops`
+pub const DEFAULT_MAX_HOPS: usize = 16;
+
+/// Ids drawn from the id generator for one machine before `create_machine` gives up
+pub const MAX_ID_ATTEMPTS: usize = 16;
+
+/// Machines listed per page when the query sets no limit
+pub const DEFAULT_PAGE_LIMIT: usize = 100;
+
+/// Most machines listed per page, whatever the query's limit
+pub const MAX_PAGE_LIMIT: usize = 1_000;
+
+/// Machines `list_machines` checks against the query per page, so sparse queries stay within the instruction limit
+pub const MAX_PAGE_SCAN: usize = 10_000;
+
+type MachineEntry<Types> = (StateMachine<Types>, StateMachineHandle<<Types as StateType>::In>, MessageReceiver<<Types as StateType>::Out>);
+
+// Messages for one machine, each with its idempotency key when deduplicating
+type KeyedBatch<In> = Vec<(In, Option<String>)>;
+
+// Whether a machine takes the message, e.g. whether its `StateType` has a conversion for it
+pub(crate) type MessageFilter<In> = Box<dyn Fn(&In) -> bool>;
+
 pub struct SimpleMachineOrchestrator<Types: StateType> {
-    next_id: u64,
-    machines: HashMap<String, (StateMachine<Types>, StateMachineHandle<Types::In>, MessageReceiver<Types::Out>)>,
+    id_generator: Box<dyn IdGenerator>,
+    // Ordered, so budgeted stepping can resume after the machine it stopped at
+    machines: BTreeMap<String, MachineEntry<Types>>,
     commands: VecDeque<Types::Out>,
-    command_handler: Box<dyn Fn(Types::Out) -> ()>,
+    command_handler: Box<dyn Fn(Types::Out)>,
+    observers: Vec<Rc<dyn StateMachineObserver<Types>>>,
+    clock: Rc<dyn Clock>,
+    logger: Rc<dyn Logger>,
+    instruction_counter: Rc<dyn InstructionCounter>,
+    metrics: Option<Rc<OrchestratorMetrics>>,
+    // Bound of the inbound channel of new machines
+    inbound_capacity: Option<(usize, OverflowPolicy)>,
+    // Machines in each tag
+    tags: HashMap<String, BTreeSet<StateMachineId>>,
+    // Messages taken by machines that do not take every message
+    filters: HashMap<StateMachineId, MessageFilter<Types::In>>,
+    router: Option<MessageRouter<Types>>,
+    max_hops: usize,
+    // Machines to step with `step_ready_machines`, now or at a time
+    schedule: Schedule,
+    // Machine ids sent by handles when they queue a message in an empty channel
+    wakes: (MessageSender<StateMachineId>, MessageReceiver<StateMachineId>),
+    // Last machine stepped by `step_machines_within`
+    step_cursor: Option<StateMachineId>,
+    // Outcomes of recently handled messages by machine and idempotency key, only kept when enabled
+    deduplication: Option<IdempotencyCache<(StateMachineId, String), Result<StepResult, OrchestratorError>>>,
 }
 
 impl<Types: StateType> SimpleMachineOrchestrator<Types> {
-    pub fn new(command_handler: Box<dyn Fn(Types::Out) -> ()>) -> SimpleMachineOrchestrator<Types> {
+    pub fn new(command_handler: Box<dyn Fn(Types::Out)>) -> SimpleMachineOrchestrator<Types> {
         SimpleMachineOrchestrator {
-            next_id: 0,
-            machines: HashMap::new(),
+            id_generator: Box::new(MonotonicIdGenerator::new()),
+            machines: BTreeMap::new(),
             commands: Default::default(),
             command_handler,
+            observers: vec![],
+            clock: Rc::new(SystemClock),
+            logger: Rc::new(PrintLogger::default()),
+            instruction_counter: Rc::new(IcInstructionCounter),
+            metrics: None,
+            inbound_capacity: None,
+            tags: HashMap::new(),
+            filters: HashMap::new(),
+            router: None,
+            max_hops: DEFAULT_MAX_HOPS,
+            schedule: Schedule::default(),
+            wakes: create_channel(),
+            step_cursor: None,
+            deduplication: None,
         }
     }
 }
 
 impl<Types: StateType> StateMachineOrchestrator<Types> for SimpleMachineOrchestrator<Types> {
-    fn create_machine(&mut self, state: Box<dyn State<Types>>) -> (StateMachineId, StateMachineHandle<Types::In>) {
-        let machine_id = self.next_id.to_string();
-        let (tx, rx) = create_channel::<Types::Out>();
+    fn create_machine(&mut self, state: Box<dyn State<Types>>) -> Result<(StateMachineId, StateMachineHandle<Types::In>), OrchestratorError> {
+        let machine_id = (0..MAX_ID_ATTEMPTS)
+            .map(|_| self.id_generator.generate())
+            .find(|machine_id| !self.machines.contains_key(machine_id))
+            .ok_or(OrchestratorError::IdGeneratorExhausted)?;
 
-        let (machine, inbound_channel) = StateMachine::new(
-            machine_id.clone(),
-            tx,
-            state,
-        );
-
-        self.machines.insert(machine_id.clone(), (machine, inbound_channel.clone(), rx));
-        self.next_id += 1;
-        return (machine_id, inbound_channel.clone());
+        Ok(self.insert_machine(machine_id, state))
     }
 
     // Pass the message to the correct state machine
     // Invoke the state machine's step function
-    fn handle_message(&mut self, message: Types::In) -> () {
-        match self.machines.get_mut(message.id()) {
-            None => {}
-            Some((machine, handle, rx)) => {
-                handle.send(message).unwrap();
-                machine.step();
-                while let Ok(Some(command)) = rx.try_receive() {
-                    self.commands.push_back(command);
+    fn handle_message(&mut self, message: Types::In) -> Result<StepResult, OrchestratorError> {
+        let key = self.deduplication.as_ref().and(message.idempotency_key()).map(|key| (message.id().clone(), key));
+        if let Some(outcome) = key.as_ref().and_then(|key| self.replayed_outcome(key)) {
+            return outcome;
+        }
+
+        let result = match self.machines.get_mut(message.id()) {
+            None => {
+                self.record_unroutable(message.id());
+                Err(OrchestratorError::MachineNotFound(message.id().clone()))
+            }
+            Some(entry) => {
+                match accepted_by(&self.filters, entry.0.id(), &message).and_then(|_| accepts_messages(&entry.0)).map(|_| entry.1.send(message)) {
+                    Err(error) => Err(error),
+                    Ok(Ok(())) => step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter),
+                    Ok(Err(error)) => {
+                        let error_kind = OrchestratorError::from_send_error(entry.0.id(), &error);
+                        log_queue_failure(&*self.logger, &*self.clock, &entry.0, Some(error.into_inner().id().clone()), &error_kind);
+                        Err(error_kind)
+                    }
                 }
             }
-        }
+        };
 
-        while let Some(v) = self.commands.pop_front() {
-            (self.command_handler)(v);
+        if let Some(key) = key {
+            self.record_outcome(key, &result);
         }
+        self.dispatch_commands();
+        result
     }
 
-    fn step_machine(&mut self, machine_id: &String) -> () {
-        match self.machines.get_mut(machine_id) {
-            None => {}
-            Some((state_machine, _, rx)) => {
-                state_machine.step();
-                while let Ok(Some(command)) = rx.try_receive() {
-                    self.commands.push_back(command);
+    fn handle_messages(&mut self, messages: Vec<Types::In>) -> BatchReport<Types::In> {
+        // Group messages per machine with their idempotency keys, keeping the order machines first appear in
+        let mut batches: Vec<(StateMachineId, KeyedBatch<Types::In>)> = vec![];
+        let mut batch_index: HashMap<StateMachineId, usize> = HashMap::new();
+        // Retried messages are answered with their first outcome, unless the machine is stepped anyway
+        let mut replays: Vec<(StateMachineId, Result<StepResult, OrchestratorError>)> = vec![];
+        // Messages the machine does not take are left out, and reported unless the machine is stepped anyway
+        let mut refused: Vec<(StateMachineId, Result<StepResult, OrchestratorError>)> = vec![];
+        let mut keys: HashSet<(StateMachineId, String)> = HashSet::new();
+        // Retried messages by key, answered once the outcome of the first message with the key in the batch is known
+        let mut duplicates = vec![];
+        for message in messages {
+            if let Err(error) = accepted_by(&self.filters, message.id(), &message) {
+                refused.push((message.id().clone(), Err(error)));
+                continue;
+            }
+            let key = self.deduplication.as_ref().and(message.idempotency_key());
+            if let Some(key) = &key {
+                let key = (message.id().clone(), key.clone());
+                if let Some(outcome) = self.replayed_outcome(&key) {
+                    replays.push((key.0.clone(), outcome.clone()));
+                    duplicates.push((key, Some(outcome)));
+                    continue;
+                }
+                // Retried within the same batch
+                if !keys.insert(key.clone()) {
+                    duplicates.push((key, None));
+                    continue;
+                }
+            }
+
+            let index = *batch_index.entry(message.id().clone()).or_insert_with(|| {
+                batches.push((message.id().clone(), vec![]));
+                batches.len() - 1
+            });
+            batches[index].1.push((message, key));
+        }
+
+        let mut results = Vec::with_capacity(batches.len());
+        let mut unsent = vec![];
+        let mut first_outcomes: HashMap<(StateMachineId, String), Result<StepResult, OrchestratorError>> = HashMap::new();
+        for (machine_id, batch) in batches {
+            let (batch, keys): (Vec<Types::In>, Vec<Option<String>>) = batch.into_iter().unzip();
+            // The outcome of stepping the machine, recorded for the messages that made it into its channel
+            let mut queued = batch.len();
+            let mut outcome = None;
+            let result = match self.machines.get_mut(&machine_id) {
+                None => {
+                    batch.iter().for_each(|message| self.record_unroutable(message.id()));
+                    Err(OrchestratorError::MachineNotFound(machine_id.clone()))
+                }
+                Some(entry) => match accepts_messages(&entry.0) {
+                    Err(error) => {
+                        queued = 0;
+                        Err(error)
+                    }
+                    Ok(()) => match entry.1.send_batch(batch) {
+                        Ok(()) => step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter),
+                        Err(error) => {
+                            let error_kind = OrchestratorError::from_send_error(entry.0.id(), &error);
+                            log_queue_failure(&*self.logger, &*self.clock, &entry.0, None, &error_kind);
+                            match error {
+                                // Part of the batch may have been queued before the channel filled up
+                                SendError::Full(tail) => {
+                                    queued -= tail.len();
+                                    unsent.extend(tail);
+                                    if queued > 0 {
+                                        outcome = Some(step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter));
+                                    }
+                                }
+                                SendError::Busy(batch) => unsent.extend(batch),
+                                SendError::Disconnected(_) => {}
+                            }
+                            Err(error_kind)
+                        }
+                    },
+                },
+            };
+
+            let outcome = outcome.unwrap_or_else(|| result.clone());
+            for (index, key) in keys.into_iter().enumerate() {
+                let Some(key) = key.map(|key| (machine_id.clone(), key)) else { continue };
+                if index < queued {
+                    self.record_outcome(key.clone(), &outcome);
+                    first_outcomes.insert(key, outcome.clone());
+                } else {
+                    first_outcomes.insert(key, result.clone());
                 }
             }
+            results.push((machine_id, result));
         }
 
-        while let Some(v) = self.commands.pop_front() {
-            (self.command_handler)(v);
+        for (machine_id, outcome) in replays.into_iter().chain(refused) {
+            if !results.iter().any(|(stepped, _)| *stepped == machine_id) {
+                results.push((machine_id, outcome));
+            }
         }
+
+        let duplicates = duplicates
+            .into_iter()
+            .filter_map(|(key, outcome)| {
+                let outcome = outcome.or_else(|| first_outcomes.get(&key).cloned())?;
+                Some((key.0, key.1, outcome))
+            })
+            .collect();
+
+        self.dispatch_commands();
+        BatchReport { results, unsent, duplicates }
+    }
+
+    fn step_machine(&mut self, machine_id: &str) -> Result<StepResult, OrchestratorError> {
+        let result = match self.machines.get_mut(machine_id) {
+            None => Err(OrchestratorError::MachineNotFound(machine_id.to_string())),
+            Some(entry) => step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter),
+        };
+
+        self.dispatch_commands();
+        result
     }
 }
 
@@ -88,18 +389,673 @@ impl<Types: StateType> SimpleMachineOrchestrator<Types> {
         }
     }
 
-    /// Step all state machines in the orchestrator. After, processes outbound commands
-    pub fn step_all_machines(&mut self) -> () {
-        self.machines.values_mut().for_each(|(machine, _, rx)| {
-            machine.step();
-            while let Ok(Some(command)) = rx.try_receive() {
-                self.commands.push_back(command);
+    /// Mutable access to the machine, e.g. to repair it. Prefer `force_transition`, `inject_message` and `edit_state`,
+    /// which record what was done in the machine's history and keep the machine scheduled.
+    pub fn get_state_machine_mut(&mut self, id: &StateMachineId) -> Option<&mut StateMachine<Types>> {
+        self.machines.get_mut(id).map(|(machine, _, _)| machine)
+    }
+
+    /// Create a machine with an id chosen by the caller, e.g. an idempotency key
+    pub fn create_machine_with_id(&mut self, machine_id: StateMachineId, state: Box<dyn State<Types>>) -> Result<(StateMachineId, StateMachineHandle<Types::In>), OrchestratorError> {
+        if self.machines.contains_key(&machine_id) {
+            return Err(OrchestratorError::DuplicateId(machine_id));
+        }
+
+        Ok(self.insert_machine(machine_id, state))
+    }
+
+    /// Replace how ids of machines created with `create_machine` are generated
+    pub fn set_id_generator(&mut self, id_generator: Box<dyn IdGenerator>) {
+        self.id_generator = id_generator;
+    }
+
+    /// Where the id generator is at, to persist across an upgrade. None when the generator can't export it.
+    pub fn export_id_generator_state(&self) -> Option<IdGeneratorState> {
+        self.id_generator.export_state()
+    }
+
+    /// Continue generating ids from an exported state, so ids are not handed out twice after an upgrade
+    pub fn restore_id_generator_state(&mut self, state: &IdGeneratorState) -> Result<(), String> {
+        self.id_generator.restore_state(state)
+    }
+
+    fn insert_machine(&mut self, machine_id: StateMachineId, state: Box<dyn State<Types>>) -> (StateMachineId, StateMachineHandle<Types::In>) {
+        let (tx, rx) = create_channel::<Types::Out>();
+
+        let inbound = match self.inbound_capacity {
+            None => create_channel(),
+            Some((capacity, policy)) => create_bounded_channel(capacity, policy),
+        };
+
+        let (mut machine, inbound_channel) = StateMachine::with_inbound_channel(
+            machine_id.clone(),
+            tx,
+            state,
+            inbound,
+        );
+        let inbound_channel = inbound_channel.with_waker(machine_id.clone(), self.wakes.0.clone());
+        machine.set_clock(self.clock.clone());
+        machine.set_created_at(self.clock.now());
+        machine.set_logger(self.logger.clone());
+        self.observers.iter().for_each(|observer| machine.add_observer(observer.clone()));
+
+        self.machines.insert(machine_id.clone(), (machine, inbound_channel.clone(), rx));
+        // The initial state still has to be initialized
+        self.schedule.ready.insert(machine_id.clone());
+        (machine_id, inbound_channel)
+    }
+
+    /// List the machines matching the query, a page at a time
+    pub fn list_machines(&self, query: &MachineQuery) -> MachinePage {
+        let after = match &query.after {
+            None => Bound::Unbounded,
+            Some(machine_id) => Bound::Excluded(machine_id.clone()),
+        };
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_LIMIT).clamp(1, MAX_PAGE_LIMIT);
+        let mut remaining = self.machines.range::<String, _>((after, Bound::Unbounded))
+            .map(|(_, (machine, _, _))| machine)
+            .peekable();
+
+        let mut machines = vec![];
+        let mut last_scanned = None;
+        for _ in 0..MAX_PAGE_SCAN {
+            if machines.len() == limit {
+                break;
+            }
+            let Some(machine) = remaining.next() else { break };
+            last_scanned = Some(machine.id());
+            if self.matches_query(machine, query) {
+                machines.push(MachineSummary {
+                    id: machine.id().clone(),
+                    state_name: machine.state().name(),
+                    status: machine.status().clone(),
+                    created_at: machine.created_at(),
+                    tags: self.machine_tags(machine.id()),
+                    steps: machine.steps(),
+                });
+            }
+        }
+        let next = match remaining.peek() {
+            Some(_) => last_scanned.cloned(),
+            None => None,
+        };
+
+        MachinePage { machines, next }
+    }
+
+    fn matches_query(&self, machine: &StateMachine<Types>, query: &MachineQuery) -> bool {
+        query.state_name.as_ref().is_none_or(|name| machine.state().name() == *name)
+            && query.status.is_none_or(|status| machine.status().kind() == status)
+            && query.created_after.is_none_or(|at| machine.created_at() >= at)
+            && query.created_before.is_none_or(|at| machine.created_at() < at)
+            && query.tags.iter().all(|tag| self.tags.get(tag).is_some_and(|machine_ids| machine_ids.contains(machine.id())))
+    }
+
+    /// Add the machine to a group that can be messaged with `multicast_to_tag`
+    pub fn tag_machine(&mut self, machine_id: &StateMachineId, tag: &str) -> Result<(), OrchestratorError> {
+        if !self.machines.contains_key(machine_id) {
+            return Err(OrchestratorError::MachineNotFound(machine_id.clone()));
+        }
+
+        self.tags.entry(tag.to_string()).or_default().insert(machine_id.clone());
+        Ok(())
+    }
+
+    pub fn untag_machine(&mut self, machine_id: &StateMachineId, tag: &str) {
+        if let Some(machine_ids) = self.tags.get_mut(tag) {
+            machine_ids.remove(machine_id);
+            if machine_ids.is_empty() {
+                self.tags.remove(tag);
+            }
+        }
+    }
+
+    /// Tags of the machine, sorted
+    pub fn machine_tags(&self, machine_id: &StateMachineId) -> Vec<String> {
+        let mut tags: Vec<String> = self.tags
+            .iter()
+            .filter(|(_, machine_ids)| machine_ids.contains(machine_id))
+            .map(|(tag, _)| tag.clone())
+            .collect();
+        tags.sort();
+        tags
+    }
+
+    /// Deliver the message to every machine, regardless of its id
+    pub fn broadcast(&mut self, message: Types::In) -> DeliveryReport {
+        let machine_ids: BTreeSet<StateMachineId> = self.machines.keys().cloned().collect();
+        self.deliver_to(machine_ids, message)
+    }
+
+    /// Deliver the message to every machine with the tag
+    pub fn multicast_to_tag(&mut self, tag: &str, message: Types::In) -> DeliveryReport {
+        let machine_ids = self.tags.get(tag).cloned().unwrap_or_default();
+        self.deliver_to(machine_ids, message)
+    }
+
+    /// Deliver the message to every machine whose current state has the name
+    pub fn multicast_to_state(&mut self, state_name: &str, message: Types::In) -> DeliveryReport {
+        let machine_ids = self.machines
+            .iter()
+            .filter(|(_, (machine, _, _))| machine.state().name() == state_name)
+            .map(|(machine_id, _)| machine_id.clone())
+            .collect();
+        self.deliver_to(machine_ids, message)
+    }
+
+    // Send a copy of the message to each machine and step it
+    fn deliver_to(&mut self, machine_ids: BTreeSet<StateMachineId>, message: Types::In) -> DeliveryReport {
+        let mut report = DeliveryReport::default();
+
+        for machine_id in machine_ids {
+            let result = match self.machines.get_mut(&machine_id) {
+                None => Err(OrchestratorError::MachineNotFound(machine_id.clone())),
+                Some(entry) => {
+                    let sent = accepted_by(&self.filters, &machine_id, &message)
+                        .and_then(|_| accepts_messages(&entry.0))
+                        .and_then(|_| entry.1.send(message.clone()).map_err(|error| OrchestratorError::from_send_error(&machine_id, &error)));
+                    sent.and_then(|_| step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter))
+                }
+            };
+
+            match result {
+                Ok(_) => report.accepted.push(machine_id),
+                Err(error) => report.rejected.push((machine_id, error)),
             }
+        }
+
+        self.dispatch_commands();
+        report
+    }
+
+    /// Replace the clock of the orchestrator and every machine, including machines created later
+    pub fn set_clock(&mut self, clock: Rc<dyn Clock>) {
+        self.machines.values_mut().for_each(|(machine, _, _)| machine.set_clock(clock.clone()));
+        self.clock = clock;
+    }
+
+    /// Replace the logger of the orchestrator and every machine, including machines created later
+    pub fn set_logger(&mut self, logger: Rc<dyn Logger>) {
+        self.machines.values_mut().for_each(|(machine, _, _)| machine.set_logger(logger.clone()));
+        self.logger = logger;
+    }
+
+    /// Register an observer on every machine, including machines created later
+    pub fn add_observer(&mut self, observer: Rc<dyn StateMachineObserver<Types>>) {
+        self.machines.values_mut().for_each(|(machine, _, _)| machine.add_observer(observer.clone()));
+        self.observers.push(observer);
+    }
+
+    /// Bound the inbound channel of machines created from now on
+    pub fn set_inbound_capacity(&mut self, capacity: usize, policy: OverflowPolicy) {
+        self.inbound_capacity = Some((capacity, policy));
+    }
+
+    /// Handle each message with an `idempotency_key` only once within `window` nanoseconds,
+    /// answering retries with the outcome of the first message. At most `capacity` keys are remembered.
+    /// Messages that were not queued, e.g. because the machine was not found, are not remembered.
+    /// Keys are scoped to the machine a message is addressed to. Only `handle_message` and `handle_messages`
+    /// deduplicate: `broadcast`, the multicasts and routed messages deliver every message they are given.
+    pub fn enable_deduplication(&mut self, capacity: usize, window: u64) {
+        self.deduplication = Some(IdempotencyCache::new(capacity, window));
+    }
+
+    /// Deliver outbound messages the router accepts to other machines within the same round,
+    /// instead of passing them to the command handler
+    pub fn set_router(&mut self, router: MessageRouter<Types>) {
+        self.router = Some(router);
+    }
+
+    /// Limit how many machine-to-machine hops are stepped in one round. Messages routed beyond the limit
+    /// are queued on their machine without stepping it, and are delivered the next time it steps.
+    pub fn set_max_hops(&mut self, max_hops: usize) {
+        self.max_hops = max_hops;
+    }
+
+    // Reject messages the filter does not take from the machine, wherever they come from
+    pub(crate) fn set_message_filter(&mut self, machine_id: StateMachineId, filter: MessageFilter<Types::In>) {
+        self.filters.insert(machine_id, filter);
+    }
+
+    /// Replace the instruction counter used to measure steps for metrics and budgeted stepping
+    pub fn set_instruction_counter(&mut self, instruction_counter: Rc<dyn InstructionCounter>) {
+        self.instruction_counter = instruction_counter;
+    }
+
+    /// Start collecting metrics for every machine. Time is measured with the orchestrator's current clock.
+    pub fn enable_metrics(&mut self) {
+        let metrics = Rc::new(OrchestratorMetrics::new(self.clock.clone()));
+        self.add_observer(metrics.clone());
+        self.metrics = Some(metrics);
+    }
+
+    pub fn metrics(&self) -> Option<&OrchestratorMetrics> {
+        self.metrics.as_deref()
+    }
+
+    /// Render the collected metrics in the Prometheus text format, e.g. for an HTTP query endpoint
+    pub fn render_prometheus_metrics(&self) -> Option<String> {
+        let metrics = self.metrics.as_ref()?;
+
+        let mut machines_by_state = BTreeMap::new();
+        for (machine, _, _) in self.machines.values() {
+            *machines_by_state.entry(machine.state().name()).or_default() += 1;
+        }
+
+        Some(metrics.render_prometheus(&machines_by_state))
+    }
+
+    /// Step all state machines in the orchestrator. After, processes outbound commands
+    pub fn step_all_machines(&mut self) {
+        self.machines.values_mut().for_each(|entry| {
+            let _ = step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter);
         });
 
+        self.dispatch_commands();
+    }
+
+    /// Step the machine until it stops transitioning, see `StateMachine::step_until_stable`
+    pub fn step_until_stable(&mut self, machine_id: &str, max_steps: u64) -> Result<StableResult, OrchestratorError> {
+        let result = match self.machines.get_mut(machine_id) {
+            None => Err(OrchestratorError::MachineNotFound(machine_id.to_string())),
+            Some(entry) => run_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter, |machine| machine.step_until_stable(max_steps)),
+        };
+
+        self.dispatch_commands();
+        result
+    }
+
+    /// Cancel the machine right away, emitting the compensating messages returned by its state's `on_cancel`.
+    /// Paused machines are cancelled too, without delivering the messages they kept. See `StateMachine::cancel`.
+    /// Senders without access to the orchestrator can send a message with a `StateMachineMessage::cancellation` instead.
+    pub fn cancel(&mut self, machine_id: &str, reason: &str) -> Result<(), OrchestratorError> {
+        let result = match self.machines.get_mut(machine_id) {
+            None => Err(OrchestratorError::MachineNotFound(machine_id.to_string())),
+            Some(entry) if !matches!(entry.0.status(), MachineStatus::Running | MachineStatus::Paused) => {
+                Err(OrchestratorError::NotRunning(machine_id.to_string(), entry.0.status().clone()))
+            }
+            Some(entry) => run_entry(entry, &mut self.commands, &mut self.schedule, None, &*self.instruction_counter, |machine| machine.cancel(reason)),
+        };
+
+        self.dispatch_commands();
+        result
+    }
+
+    /// Stop stepping the machine until it is resumed. Messages sent to it are kept in its inbound channel.
+    pub fn pause(&mut self, machine_id: &str) -> Result<(), OrchestratorError> {
+        let (machine, _, _) = self.machines.get_mut(machine_id)
+            .ok_or_else(|| OrchestratorError::MachineNotFound(machine_id.to_string()))?;
+        if *machine.status() != MachineStatus::Running {
+            return Err(OrchestratorError::NotRunning(machine_id.to_string(), machine.status().clone()));
+        }
+
+        let _ = machine.pause();
+        self.schedule.ready.remove(machine_id);
+        Ok(())
+    }
+
+    /// Step the machine again after `pause`. It is ready to handle the messages it was sent meanwhile.
+    pub fn resume(&mut self, machine_id: &str) -> Result<(), OrchestratorError> {
+        let (machine, _, _) = self.machines.get_mut(machine_id)
+            .ok_or_else(|| OrchestratorError::MachineNotFound(machine_id.to_string()))?;
+        if *machine.status() != MachineStatus::Paused {
+            return Err(OrchestratorError::NotPaused(machine_id.to_string(), machine.status().clone()));
+        }
+
+        let _ = machine.resume();
+        self.schedule.ready.insert(machine_id.to_string());
+        Ok(())
+    }
+
+    /// Move the machine to the state without calling `advance`, recording the reason in its history.
+    /// See `StateMachine::force_transition`.
+    pub fn force_transition(&mut self, machine_id: &str, state: Box<dyn State<Types>>, reason: &str) -> Result<(), OrchestratorError> {
+        let entry = self.machines.get_mut(machine_id)
+            .ok_or_else(|| OrchestratorError::MachineNotFound(machine_id.to_string()))?;
+
+        run_entry(entry, &mut self.commands, &mut self.schedule, None, &*self.instruction_counter, |machine| machine.force_transition(state, reason))
+            .map_err(|error| match error {
+                OrchestratorError::StepFailed(machine_id, error) => OrchestratorError::AdminActionFailed(machine_id, error),
+                error => error,
+            })
+    }
+
+    /// Deliver the message to its machine ahead of the queue, recording the reason in the machine's history.
+    /// The machine advances on its next step. Fails with `NotRunning` unless the machine is running or paused.
+    /// See `StateMachine::inject_message`.
+    pub fn inject_message(&mut self, message: Types::In, reason: &str) -> Result<(), OrchestratorError> {
+        let machine_id = message.id().clone();
+        let entry = self.machines.get_mut(&machine_id)
+            .ok_or_else(|| OrchestratorError::MachineNotFound(machine_id.clone()))?;
+        accepted_by(&self.filters, &machine_id, &message)?;
+        accepts_messages(&entry.0)?;
+
+        run_entry(entry, &mut self.commands, &mut self.schedule, None, &*self.instruction_counter, |machine| machine.inject_message(message, reason))?;
+        self.mark_ready_if_running(&machine_id);
+        Ok(())
+    }
+
+    /// Edit the machine's current state in place if it is a `T`, recording the reason in the machine's history.
+    /// The machine advances on its next step.
+    pub fn edit_state<T>(&mut self, machine_id: &str, reason: &str, edit: impl FnOnce(&mut T)) -> Result<(), OrchestratorError>
+        where T: State<Types>
+    {
+        let (machine, _, _) = self.machines.get_mut(machine_id)
+            .ok_or_else(|| OrchestratorError::MachineNotFound(machine_id.to_string()))?;
+        if machine.downcast_state::<T>().is_none() {
+            return Err(OrchestratorError::StateMismatch(machine_id.to_string(), machine.state().name()));
+        }
+
+        machine.edit_state(reason, edit).map_err(|error| OrchestratorError::AdminActionFailed(machine_id.to_string(), error))?;
+        self.mark_ready_if_running(machine_id);
+        Ok(())
+    }
+
+    fn mark_ready_if_running(&mut self, machine_id: &str) {
+        if self.machines.get(machine_id).is_some_and(|(machine, _, _)| *machine.status() == MachineStatus::Running) {
+            self.schedule.ready.insert(machine_id.to_string());
+        }
+    }
+
+    /// Mark the machine to be stepped by the next `step_ready_machines`
+    pub fn wake(&mut self, machine_id: &StateMachineId) -> Result<(), OrchestratorError> {
+        if !self.machines.contains_key(machine_id) {
+            return Err(OrchestratorError::MachineNotFound(machine_id.clone()));
+        }
+
+        self.schedule.ready.insert(machine_id.clone());
+        Ok(())
+    }
+
+    /// Wake the machine once the orchestrator's clock reaches `at`, in nanoseconds
+    pub fn wake_at(&mut self, machine_id: &StateMachineId, at: u64) -> Result<(), OrchestratorError> {
+        if !self.machines.contains_key(machine_id) {
+            return Err(OrchestratorError::MachineNotFound(machine_id.clone()));
+        }
+
+        self.schedule.timers.insert((at, machine_id.clone()));
+        Ok(())
+    }
+
+    /// Machines `step_ready_machines` would step now, not counting timers that became due
+    pub fn ready_machines(&mut self) -> Vec<StateMachineId> {
+        self.collect_wakes();
+        self.schedule.ready.iter().cloned().collect()
+    }
+
+    /// Step only the machines that can make progress: new machines, machines with queued messages,
+    /// machines that just transitioned, machines whose timer is due and machines woken with `wake`.
+    /// Machines that become ready while stepping are stepped by the next call.
+    pub fn step_ready_machines(&mut self) -> Vec<(StateMachineId, Result<StepResult, OrchestratorError>)> {
+        self.collect_wakes();
+
+        let now = self.clock.now();
+        while let Some((at, machine_id)) = self.schedule.timers.first().cloned() {
+            if at > now {
+                break;
+            }
+            self.schedule.timers.remove(&(at, machine_id.clone()));
+            if self.machines.contains_key(&machine_id) {
+                self.schedule.ready.insert(machine_id);
+            }
+        }
+
+        let ready = std::mem::take(&mut self.schedule.ready);
+        let mut results = Vec::with_capacity(ready.len());
+        for machine_id in ready {
+            if let Some(entry) = self.machines.get_mut(&machine_id) {
+                let result = step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter);
+                results.push((machine_id, result));
+            }
+        }
+
+        self.dispatch_commands();
+        results
+    }
+
+    /// Step machines until the instruction counter is about to reach `instruction_limit`, stepping every machine at most once.
+    /// Stops before a step that is expected to cross the limit, judging by the most expensive step so far.
+    /// Machines receiving routed messages are stepped within the same budget; once it is spent the messages stay
+    /// queued and the machines are ready for `step_ready_machines`.
+    /// Each call resumes after the last machine the previous call stepped, so every machine is stepped in turn.
+    pub fn step_machines_within(&mut self, instruction_limit: u64) -> BudgetedStepReport {
+        let mut stepped = 0;
+        let mut budget_exhausted = false;
+        let mut max_step_cost = 0;
+        let mut last = self.instruction_counter.instructions();
+
+        while stepped < self.machines.len() {
+            if last.saturating_add(max_step_cost) >= instruction_limit {
+                budget_exhausted = true;
+                break;
+            }
+
+            let after = match &self.step_cursor {
+                None => Bound::Unbounded,
+                Some(machine_id) => Bound::Excluded(machine_id.clone()),
+            };
+            let next = self.machines.range::<String, _>((after, Bound::Unbounded)).next()
+                .or_else(|| self.machines.iter().next())
+                .map(|(machine_id, _)| machine_id.clone());
+            let Some(machine_id) = next else { break };
+
+            if let Some(entry) = self.machines.get_mut(&machine_id) {
+                let _ = step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter);
+            }
+            self.step_cursor = Some(machine_id);
+            stepped += 1;
+
+            let now = self.instruction_counter.instructions();
+            max_step_cost = max_step_cost.max(now.saturating_sub(last));
+            last = now;
+        }
+
+        self.dispatch_commands_within(Some(InstructionBudget { limit: instruction_limit, max_step_cost }));
+        BudgetedStepReport { stepped, budget_exhausted }
+    }
+
+    fn replayed_outcome(&mut self, key: &(StateMachineId, String)) -> Option<Result<StepResult, OrchestratorError>> {
+        let now = self.clock.now();
+        self.deduplication.as_mut()?.get(key, now)
+    }
+
+    fn record_outcome(&mut self, key: (StateMachineId, String), outcome: &Result<StepResult, OrchestratorError>) {
+        // Retries of messages that never reached the machine should be tried again
+        let queued = matches!(outcome, Ok(_) | Err(OrchestratorError::StepFailed(_, _)));
+        let now = self.clock.now();
+        if let (true, Some(deduplication)) = (queued, self.deduplication.as_mut()) {
+            deduplication.insert(key, now, outcome.clone());
+        }
+    }
+
+    fn record_unroutable(&self, message_id: &str) {
+        if let Some(metrics) = &self.metrics {
+            metrics.record_unroutable();
+        }
+        if self.logger.enabled(LogLevel::Warn) {
+            self.logger.log(LogRecord {
+                level: LogLevel::Warn,
+                timestamp: self.clock.now(),
+                machine_id: None,
+                state_name: None,
+                message_id: Some(message_id.to_string()),
+                message: "No machine to route message to".to_string(),
+            });
+        }
+    }
+
+    fn dispatch_commands(&mut self) {
+        self.dispatch_commands_within(None);
+    }
 
-        while let Some(v) = self.commands.pop_front() {
-            (self.command_handler)(v);
+    // Pass commands to the command handler, or deliver them to other machines when routed.
+    // Commands emitted by routed deliveries are handled in waves, one wave per hop.
+    fn dispatch_commands_within(&mut self, budget: Option<InstructionBudget>) {
+        // Keep the wake channel from growing when ready machines are not stepped
+        self.collect_wakes();
+
+        let mut hops = 0;
+        while !self.commands.is_empty() {
+            hops += 1;
+            let commands: Vec<Types::Out> = self.commands.drain(..).collect();
+            let routed: Vec<Result<Types::In, Types::Out>> = match &self.router {
+                None => commands.into_iter().map(Err).collect(),
+                Some(router) => commands.into_iter().map(router).collect(),
+            };
+
+            for command in routed {
+                match command {
+                    Ok(message) => self.route(message, hops, budget),
+                    Err(command) => (self.command_handler)(command),
+                }
+            }
+        }
+    }
+
+    // Mark running machines that were sent messages as ready, unless they already handled them.
+    // Paused machines are marked ready when resumed.
+    fn collect_wakes(&mut self) {
+        while let Ok(machine_id) = self.wakes.1.try_receive() {
+            let runnable = |(machine, handle, _): &MachineEntry<Types>| *machine.status() == MachineStatus::Running && handle.pending() > 0;
+            if self.machines.get(&machine_id).is_some_and(runnable) {
+                self.schedule.ready.insert(machine_id);
+            }
+        }
+    }
+
+    // Deliver a message emitted by another machine, stepping the receiver unless the hop limit is reached
+    // or the instruction budget is spent
+    fn route(&mut self, message: Types::In, hops: usize, budget: Option<InstructionBudget>) {
+        let entry = match self.machines.get_mut(message.id()) {
+            None => return self.record_unroutable(message.id()),
+            Some(entry) => entry,
+        };
+
+        let message_id = message.id().clone();
+        if let Err(error) = accepted_by(&self.filters, &message_id, &message).and_then(|_| accepts_messages(&entry.0)) {
+            log_queue_failure(&*self.logger, &*self.clock, &entry.0, Some(message_id), &error);
+            return;
+        }
+        if let Err(error) = entry.1.send(message) {
+            let error_kind = OrchestratorError::from_send_error(entry.0.id(), &error);
+            log_queue_failure(&*self.logger, &*self.clock, &entry.0, Some(message_id), &error_kind);
+            return;
+        }
+
+        if hops > self.max_hops {
+            if self.logger.enabled(LogLevel::Warn) {
+                self.logger.log(LogRecord {
+                    level: LogLevel::Warn,
+                    timestamp: self.clock.now(),
+                    machine_id: Some(entry.0.id().clone()),
+                    state_name: Some(entry.0.state().name()),
+                    message_id: Some(message_id),
+                    message: format!("Routed message exceeded {} hops, queued until the next step", self.max_hops),
+                });
+            }
+            return;
         }
+        if budget.is_some_and(|budget| budget.is_spent(&*self.instruction_counter)) {
+            return;
+        }
+
+        // Step failures are reported to the machine's observers and logger
+        let _ = step_entry(entry, &mut self.commands, &mut self.schedule, self.metrics.as_deref(), &*self.instruction_counter);
+    }
+}
+
+// Instructions `step_machines_within` may use, and the most expensive step it measured
+#[derive(Clone, Copy)]
+struct InstructionBudget {
+    limit: u64,
+    max_step_cost: u64,
+}
+
+impl InstructionBudget {
+    fn is_spent(&self, instruction_counter: &dyn InstructionCounter) -> bool {
+        instruction_counter.instructions().saturating_add(self.max_step_cost) >= self.limit
+    }
+}
+
+#[derive(Default)]
+struct Schedule {
+    ready: BTreeSet<StateMachineId>,
+    // Machines to wake at a time, by time
+    timers: BTreeSet<(u64, StateMachineId)>,
+}
+
+fn step_entry<Types: StateType>(
+    entry: &mut MachineEntry<Types>,
+    commands: &mut VecDeque<Types::Out>,
+    schedule: &mut Schedule,
+    metrics: Option<&OrchestratorMetrics>,
+    instruction_counter: &dyn InstructionCounter,
+) -> Result<StepResult, OrchestratorError> {
+    run_entry(entry, commands, schedule, metrics, instruction_counter, StateMachine::step)
+}
+
+// Run the machine, queue the commands it emitted and measure the instructions it used when given `metrics`.
+// Admin actions pass no metrics, so they don't count as steps.
+// The machine stays ready while it is running and has a state to initialize, and is woken when a failed step is due a retry.
+fn run_entry<Types: StateType, R>(
+    (machine, handle, rx): &mut MachineEntry<Types>,
+    commands: &mut VecDeque<Types::Out>,
+    schedule: &mut Schedule,
+    metrics: Option<&OrchestratorMetrics>,
+    instruction_counter: &dyn InstructionCounter,
+    run: impl FnOnce(&mut StateMachine<Types>) -> Result<R, StateMachineError>,
+) -> Result<R, OrchestratorError> {
+    let start = instruction_counter.instructions();
+    let result = run(machine);
+    if let Some(metrics) = metrics {
+        metrics.record_step_instructions(instruction_counter.instructions().saturating_sub(start));
+    }
+
+    while let Ok(command) = rx.try_receive() {
+        commands.push_back(command);
     }
-}
\ No newline at end of file
+
+    schedule.ready.remove(machine.id());
+    let running = *machine.status() == MachineStatus::Running;
+    if result.is_ok() && running && !machine.is_state_initialized() {
+        schedule.ready.insert(machine.id().clone());
+    }
+    // Messages left in the channel, e.g. when it was busy, won't wake the machine again
+    if running && handle.pending() > 0 {
+        schedule.ready.insert(machine.id().clone());
+    }
+    if let Some(at) = machine.next_retry_at() {
+        schedule.timers.insert((at, machine.id().clone()));
+    }
+
+    result.map_err(|error| OrchestratorError::StepFailed(machine.id().clone(), error))
+}
+
+// Messages a machine without a filter takes are left to its state to deliver
+fn accepted_by<In>(filters: &HashMap<StateMachineId, MessageFilter<In>>, machine_id: &StateMachineId, message: &In) -> Result<(), OrchestratorError> {
+    match filters.get(machine_id) {
+        Some(filter) if !filter(message) => Err(OrchestratorError::MessageNotAccepted(machine_id.clone())),
+        _ => Ok(()),
+    }
+}
+
+// Paused machines keep messages for when they resume, others would never handle them
+fn accepts_messages<Types: StateType>(machine: &StateMachine<Types>) -> Result<(), OrchestratorError> {
+    match machine.status() {
+        MachineStatus::Running | MachineStatus::Paused => Ok(()),
+        status => Err(OrchestratorError::NotRunning(machine.id().clone(), status.clone())),
+    }
+}
+
+fn log_queue_failure<Types: StateType>(logger: &dyn Logger, clock: &dyn Clock, machine: &StateMachine<Types>, message_id: Option<String>, error: &OrchestratorError) {
+    if logger.enabled(LogLevel::Warn) {
+        logger.log(LogRecord {
+            level: LogLevel::Warn,
+            timestamp: clock.now(),
+            machine_id: Some(machine.id().clone()),
+            state_name: Some(machine.state().name()),
+            message_id,
+            message: format!("Failed to queue message: {:?}", error),
+        });
+    }
+}
@@ -0,0 +1,98 @@
+// Listing the orders of example 8, e.g. to show an admin every order still awaiting payment.
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::clock::ManualClock;
+    use crate::state_machine::{MachineStatus, StatusKind};
+    use crate::state_machine_orchestrator::{MachineQuery, SimpleMachineOrchestrator, MAX_PAGE_LIMIT, MAX_PAGE_SCAN};
+    use crate::tests::example_8_cancellation::{AwaitingPayment, MachineTypes, Paid};
+
+    fn orders(clock: &ManualClock) -> SimpleMachineOrchestrator<MachineTypes> {
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(|_| {}));
+        orchestrator.set_clock(Rc::new(clock.clone()));
+
+        for (index, id) in ["a", "b", "c", "d", "e"].into_iter().enumerate() {
+            clock.set(index as u64 * 10);
+            if id == "c" {
+                orchestrator.create_machine_with_id(id.to_string(), Box::new(Paid { order_id: id.to_string(), amount: 5 })).unwrap();
+            } else {
+                orchestrator.create_machine_with_id(id.to_string(), Box::new(AwaitingPayment {})).unwrap();
+            }
+        }
+        orchestrator
+    }
+
+    fn ids(orchestrator: &SimpleMachineOrchestrator<MachineTypes>, query: MachineQuery) -> Vec<String> {
+        orchestrator.list_machines(&query).machines.into_iter().map(|summary| summary.id).collect()
+    }
+
+    #[test]
+    pub fn it_filters_machines() {
+        let clock = ManualClock::new(0);
+        let mut orchestrator = orders(&clock);
+        orchestrator.cancel("d", "customer request").unwrap();
+        orchestrator.tag_machine(&"a".to_string(), "vip").unwrap();
+        orchestrator.tag_machine(&"e".to_string(), "vip").unwrap();
+
+        let awaiting_payment = MachineQuery { state_name: Some("AwaitingPayment".to_string()), ..Default::default() };
+        assert_eq!(ids(&orchestrator, awaiting_payment.clone()), vec!["a", "b", "d", "e"]);
+        assert_eq!(ids(&orchestrator, MachineQuery { status: Some(StatusKind::Running), ..awaiting_payment }), vec!["a", "b", "e"]);
+        assert_eq!(ids(&orchestrator, MachineQuery { created_after: Some(10), created_before: Some(30), ..Default::default() }), vec!["b", "c"]);
+        assert_eq!(ids(&orchestrator, MachineQuery { tags: vec!["vip".to_string()], ..Default::default() }), vec!["a", "e"]);
+
+        let summary = orchestrator.list_machines(&MachineQuery { after: Some("c".to_string()), limit: Some(1), ..Default::default() }).machines.remove(0);
+        assert_eq!(summary.id, "d");
+        assert_eq!(summary.state_name, "AwaitingPayment");
+        assert_eq!(summary.status, MachineStatus::Cancelled { reason: "customer request".to_string() });
+        assert_eq!(summary.created_at, 30);
+    }
+
+    #[test]
+    pub fn it_pages_through_machines() {
+        let clock = ManualClock::new(0);
+        let orchestrator = orders(&clock);
+
+        let first = orchestrator.list_machines(&MachineQuery { limit: Some(2), ..Default::default() });
+        assert_eq!(first.machines.len(), 2);
+        assert_eq!(first.next, Some("b".to_string()));
+
+        let second = orchestrator.list_machines(&MachineQuery { limit: Some(2), after: first.next, ..Default::default() });
+        assert_eq!(second.next, Some("d".to_string()));
+
+        let last = orchestrator.list_machines(&MachineQuery { limit: Some(2), after: second.next, ..Default::default() });
+        assert_eq!(last.machines.len(), 1);
+        assert_eq!(last.next, None);
+    }
+
+    #[test]
+    pub fn it_clamps_the_page_limit() {
+        let clock = ManualClock::new(0);
+        let orchestrator = orders(&clock);
+
+        let page = orchestrator.list_machines(&MachineQuery { limit: Some(0), ..Default::default() });
+        assert_eq!(page.machines.len(), 1);
+        assert_eq!(page.next, Some("a".to_string()));
+
+        let page = orchestrator.list_machines(&MachineQuery { limit: Some(MAX_PAGE_LIMIT + 1), ..Default::default() });
+        assert_eq!(page.machines.len(), 5);
+    }
+
+    #[test]
+    pub fn it_stops_scanning_sparse_queries() {
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(|_| {}));
+        for index in 0..=MAX_PAGE_SCAN {
+            orchestrator.create_machine_with_id(format!("{:05}", index), Box::new(AwaitingPayment {})).unwrap();
+        }
+
+        let paid = MachineQuery { state_name: Some("Paid".to_string()), ..Default::default() };
+        let first = orchestrator.list_machines(&paid);
+        assert!(first.machines.is_empty());
+        assert_eq!(first.next, Some(format!("{:05}", MAX_PAGE_SCAN - 1)));
+
+        let last = orchestrator.list_machines(&MachineQuery { after: first.next, ..paid });
+        assert!(last.machines.is_empty());
+        assert_eq!(last.next, None);
+    }
+}
@@ -0,0 +1,76 @@
+// Repairing the orders of example 8 by hand, as an administrator would after a bug.
+
+#[cfg(test)]
+mod test {
+    use crate::history::AdminAction;
+    use crate::state_machine::{MachineStatus, StepResult};
+    use crate::state_machine_orchestrator::{OrchestratorError, SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::example_8_cancellation::{AwaitingPayment, MachineTypes, Paid, Payment};
+
+    fn orchestrator() -> (SimpleMachineOrchestrator<MachineTypes>, String) {
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(|_| {}));
+        let (id, _) = orchestrator.create_machine_with_id("order".to_string(), Box::new(AwaitingPayment {})).unwrap();
+        orchestrator.get_state_machine_mut(&id).unwrap().enable_history(10);
+        orchestrator.step_ready_machines();
+        (orchestrator, id)
+    }
+
+    #[test]
+    pub fn it_repairs_machines_and_records_each_action() {
+        let (mut orchestrator, id) = orchestrator();
+
+        orchestrator.force_transition(&id, Box::new(Paid { order_id: id.clone(), amount: 0 }), "payment was lost").unwrap();
+        assert_eq!(orchestrator.ready_machines(), vec![id.clone()]);
+        orchestrator.inject_message(Payment { machine_id: id.clone(), amount: 7 }, "replay lost payment").unwrap();
+        orchestrator.edit_state::<Paid>(&id, "fee was not applied", |paid| paid.amount -= 2).unwrap();
+
+        assert_eq!(orchestrator.step_ready_machines(), vec![(id.clone(), Ok(StepResult::Running))]);
+        let machine = orchestrator.get_state_machine(&id).unwrap();
+        assert_eq!(machine.downcast_state::<Paid>(), Some(&Paid { order_id: id.clone(), amount: 5 }));
+
+        let records = machine.history().unwrap().export();
+        assert_eq!(records.len(), 3);
+        assert_eq!((records[0].from.as_str(), records[0].to.as_deref()), ("AwaitingPayment", Some("Paid")));
+        assert_eq!(records[0].admin_action, Some(AdminAction::ForceTransition { reason: "payment was lost".to_string() }));
+        assert_eq!(records[1].messages, vec![Payment { machine_id: id.clone(), amount: 7 }]);
+        assert_eq!(records[1].admin_action, Some(AdminAction::InjectMessage { reason: "replay lost payment".to_string() }));
+        assert_eq!(records[2].admin_action, Some(AdminAction::EditState { reason: "fee was not applied".to_string() }));
+    }
+
+    #[test]
+    pub fn it_revives_cancelled_machines_with_a_forced_transition() {
+        let (mut orchestrator, id) = orchestrator();
+        orchestrator.cancel(&id, "cancelled by mistake").unwrap();
+
+        let payment = Payment { machine_id: id.clone(), amount: 1 };
+        let error = orchestrator.inject_message(payment.clone(), "replay lost payment").unwrap_err();
+        assert!(matches!(error, OrchestratorError::NotRunning(_, MachineStatus::Cancelled { .. })));
+        assert_eq!(orchestrator.get_state_machine(&id).unwrap().history().unwrap().len(), 1);
+
+        orchestrator.force_transition(&id, Box::new(AwaitingPayment {}), "undo cancellation").unwrap();
+        orchestrator.inject_message(payment, "replay lost payment").unwrap();
+
+        assert_eq!(orchestrator.get_state_machine(&id).unwrap().status(), &MachineStatus::Running);
+        assert_eq!(orchestrator.handle_message(Payment { machine_id: id.clone(), amount: 1 }), Ok(StepResult::Running));
+    }
+
+    #[test]
+    pub fn it_rejects_edits_of_another_state() {
+        let (mut orchestrator, id) = orchestrator();
+
+        let error = orchestrator.edit_state::<Paid>(&id, "wrong state", |paid| paid.amount = 0).unwrap_err();
+        assert_eq!(error, OrchestratorError::StateMismatch(id.clone(), "AwaitingPayment".to_string()));
+        assert!(orchestrator.get_state_machine(&id).unwrap().history().unwrap().is_empty());
+        assert_eq!(orchestrator.inject_message(Payment { machine_id: "missing".to_string(), amount: 1 }, "typo"), Err(OrchestratorError::MachineNotFound("missing".to_string())));
+    }
+
+    #[test]
+    pub fn it_refuses_repairs_the_journal_could_not_replay() {
+        let (mut orchestrator, id) = orchestrator();
+        orchestrator.get_state_machine_mut(&id).unwrap().enable_journal();
+
+        let error = orchestrator.force_transition(&id, Box::new(Paid { order_id: id.clone(), amount: 0 }), "payment was lost").unwrap_err();
+        assert!(matches!(error, OrchestratorError::AdminActionFailed(_, _)));
+        assert!(orchestrator.get_state_machine(&id).unwrap().downcast_state::<AwaitingPayment>().is_some());
+    }
+}
@@ -0,0 +1,167 @@
+use crate::state::{DeliveryStatus, State, StateMachineMessage, StateType, Transition};
+use crate::transition_table::{StateTransitions, TransitionRule, TransitionTable};
+
+// A turnstile described by transition tables. A coin unlocks it and a push locks it again,
+// until it has let everyone through and closes for the day.
+
+const CAPACITY: u64 = 2;
+
+#[derive(Debug, PartialEq)]
+pub struct Locked {
+    pub gate: String,
+    pub passed: u64,
+    pub paid: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Unlocked {
+    pub gate: String,
+    pub passed: u64,
+    pub pushed: bool,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum TurnstileEvent {
+    Coin { machine_id: String },
+    Push { machine_id: String },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum GateCommand {
+    Open { gate: String },
+    Close { gate: String },
+}
+
+impl StateMachineMessage for TurnstileEvent {
+    fn id(&self) -> &String {
+        match self {
+            TurnstileEvent::Coin { machine_id } => machine_id,
+            TurnstileEvent::Push { machine_id } => machine_id,
+        }
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+}
+
+impl StateMachineMessage for GateCommand {
+    fn id(&self) -> &String {
+        match self {
+            GateCommand::Open { gate } => gate,
+            GateCommand::Close { gate } => gate,
+        }
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+}
+
+pub struct MachineTypes {}
+
+impl StateType for MachineTypes {
+    type In = TurnstileEvent;
+    type Out = GateCommand;
+}
+
+impl Locked {
+    pub fn new(gate: &str) -> Self {
+        Locked { gate: gate.to_string(), passed: 0, paid: false }
+    }
+}
+
+impl State<MachineTypes> for Locked {
+    fn deliver(&mut self, message: TurnstileEvent) -> DeliveryStatus<TurnstileEvent, String> {
+        match message {
+            TurnstileEvent::Coin { .. } => {
+                self.paid = true;
+                DeliveryStatus::Delivered
+            }
+            _ => DeliveryStatus::Unexpected(message)
+        }
+    }
+
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        Ok(Transition::Same)
+    }
+
+    fn transition_table(&self) -> Option<Box<dyn StateTransitions<MachineTypes>>> {
+        Some(Box::new(TransitionTable::new()
+            .with_rule(TransitionRule::next("coin", |state: &Locked| state.paid, |state| Unlocked { gate: state.gate.clone(), passed: state.passed, pushed: false })
+                .with_action(|state| vec![GateCommand::Open { gate: state.gate.clone() }]))))
+    }
+}
+
+impl State<MachineTypes> for Unlocked {
+    fn deliver(&mut self, message: TurnstileEvent) -> DeliveryStatus<TurnstileEvent, String> {
+        match message {
+            TurnstileEvent::Push { .. } => {
+                self.pushed = true;
+                DeliveryStatus::Delivered
+            }
+            _ => DeliveryStatus::Unexpected(message)
+        }
+    }
+
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        Ok(Transition::Same)
+    }
+
+    fn transition_table(&self) -> Option<Box<dyn StateTransitions<MachineTypes>>> {
+        Some(Box::new(TransitionTable::new()
+            .with_rule(TransitionRule::terminal("last push", |state: &Unlocked| state.pushed && state.passed + 1 >= CAPACITY)
+                .with_action(|state| vec![GateCommand::Close { gate: state.gate.clone() }]))
+            .with_rule(TransitionRule::next("push", |state: &Unlocked| state.pushed, |state| Locked { gate: state.gate.clone(), passed: state.passed + 1, paid: false })
+                .with_action(|state| vec![GateCommand::Close { gate: state.gate.clone() }]))))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message_channel::create_channel;
+    use crate::state::State;
+    use crate::state_machine::{StateMachine, StepResult};
+    use crate::tests::example_12_transition_tables::{GateCommand, Locked, TurnstileEvent, Unlocked};
+    use crate::transition_table::to_mermaid;
+
+    fn coin() -> TurnstileEvent {
+        TurnstileEvent::Coin { machine_id: "turnstile".to_string() }
+    }
+
+    fn push() -> TurnstileEvent {
+        TurnstileEvent::Push { machine_id: "turnstile".to_string() }
+    }
+
+    #[test]
+    pub fn it_follows_transition_tables_when_stepping() {
+        let (sender, commands) = create_channel();
+        let (mut machine, handle) = StateMachine::new("turnstile".to_string(), sender, Box::new(Locked::new("north")));
+
+        handle.send(coin()).unwrap();
+        assert_eq!(machine.step(), Ok(StepResult::Running));
+        assert!(machine.downcast_state::<Unlocked>().is_some());
+        assert_eq!(commands.try_receive(), Ok(GateCommand::Open { gate: "north".to_string() }));
+
+        handle.send(push()).unwrap();
+        machine.step().unwrap();
+        assert_eq!(machine.downcast_state::<Locked>(), Some(&Locked { gate: "north".to_string(), passed: 1, paid: false }));
+        assert_eq!(commands.try_receive(), Ok(GateCommand::Close { gate: "north".to_string() }));
+
+        handle.send(coin()).unwrap();
+        machine.step().unwrap();
+        handle.send(push()).unwrap();
+        assert_eq!(machine.step(), Ok(StepResult::Terminated));
+    }
+
+    #[test]
+    pub fn it_exports_mermaid_diagrams() {
+        let locked = Locked::new("north").transition_table().unwrap();
+        let unlocked = Unlocked { gate: "north".to_string(), passed: 0, pushed: false }.transition_table().unwrap();
+
+        assert_eq!(
+            to_mermaid(&[&*locked, &*unlocked]),
+            "stateDiagram-v2\n    Locked --> Unlocked: coin\n    Unlocked --> [*]: last push\n    Unlocked --> Locked: push\n"
+        );
+    }
+}
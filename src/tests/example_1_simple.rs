@@ -39,7 +39,7 @@ impl State<MachineTypes> for Blue {
 mod test {
     use crate::tests::example_1_simple::{Blue, Red};
     use crate::message_channel::create_channel;
-    use crate::state_machine::StateMachine;
+    use crate::state_machine::{StableResult, StateMachine};
 
     #[test]
     pub fn test() {
@@ -47,12 +47,26 @@ mod test {
         let (mut machine, _) = StateMachine::new("simple".to_string(), sender, Box::new(Red::new()));
 
         assert_eq!(machine.downcast_state::<Red>(), Some(&Red {}));
-        machine.step();
+        let _ = machine.step();
 
         assert_eq!(machine.downcast_state::<Blue>(), Some(&Blue {}));
-        machine.step();
+        let _ = machine.step();
 
         assert_eq!(machine.downcast_state::<Red>(), Some(&Red {}));
-        machine.step();
+        let _ = machine.step();
+    }
+
+    #[test]
+    pub fn it_detects_transition_loops() {
+        let (sender, _) = create_channel();
+        let (mut machine, _) = StateMachine::new("simple".to_string(), sender, Box::new(Red::new()));
+
+        let error = machine.step_until_stable(10).unwrap_err();
+        assert_eq!(error.message(), "Transition loop detected, returned to state Red");
+        assert_eq!(machine.steps(), 2);
+
+        let (sender, _) = create_channel();
+        let (mut machine, _) = StateMachine::new("simple".to_string(), sender, Box::new(Red::new()));
+        assert_eq!(machine.step_until_stable(1), Ok(StableResult::StepLimitReached));
     }
 }
\ No newline at end of file
@@ -1,10 +1,14 @@
-use crate::state::{DeliveryStatus, NoMessage, State, StateMachineMessage, StateType, Transition};
+use candid::CandidType;
+use serde::Deserialize;
+
+use crate::journal::StateSnapshot;
+use crate::state::{BoxedState, DeliveryStatus, NoMessage, State, StateMachineMessage, StateType, Transition};
 use crate::state::Transition::{Same, Terminal};
 
 // A Simple state machine that alternates between Red and Blue states.
 
 // State Red
-#[derive(Debug, PartialEq)]
+#[derive(CandidType, Deserialize, Debug, PartialEq)]
 pub struct RedMessageState {
     pub count: u64,
 }
@@ -15,7 +19,7 @@ pub struct BlueMessageState {
     pub count: u64,
 }
 
-#[derive(Clone, Debug)]
+#[derive(CandidType, Deserialize, Clone, Debug)]
 pub enum SimpleMessage {
     IncrementRed { machine_id: String },
     IncrementBlue { machine_id: String },
@@ -40,6 +44,13 @@ pub struct MachineTypes {}
 impl StateType for MachineTypes {
     type In = SimpleMessage;
     type Out = NoMessage;
+
+    fn restore(snapshot: &StateSnapshot) -> Option<BoxedState<Self>> {
+        match snapshot.name.as_str() {
+            "RedMessageState" => Some(Box::new(snapshot.decode::<RedMessageState>()?)),
+            _ => None,
+        }
+    }
 }
 
 impl RedMessageState {
@@ -66,6 +77,10 @@ impl State<MachineTypes> for RedMessageState {
         }
         Ok(Transition::Next(Box::new(BlueMessageState { count: 0 })))
     }
+
+    fn snapshot(&self) -> Option<StateSnapshot> {
+        StateSnapshot::new(self.name(), self)
+    }
 }
 
 impl State<MachineTypes> for BlueMessageState {
@@ -73,7 +88,7 @@ impl State<MachineTypes> for BlueMessageState {
         println!("{:?} {:?}", self, message);
 
         match message {
-            SimpleMessage::IncrementBlue { machine_id } => {
+            SimpleMessage::IncrementBlue { .. } => {
                 self.count += 1;
                 print!("Blue count: {}", self.count);
                 DeliveryStatus::Delivered
@@ -90,10 +105,18 @@ impl State<MachineTypes> for BlueMessageState {
     }
 }
 
+pub fn red(machine_id: &str) -> SimpleMessage {
+    SimpleMessage::IncrementRed { machine_id: machine_id.to_string() }
+}
+
+pub fn blue(machine_id: &str) -> SimpleMessage {
+    SimpleMessage::IncrementBlue { machine_id: machine_id.to_string() }
+}
+
 #[cfg(test)]
 mod test {
     use crate::tests::example_2_simple_inbound_messages::{BlueMessageState, RedMessageState, SimpleMessage};
-    use crate::message_channel::create_channel;
+    use crate::message_channel::{create_channel, SendError};
     use crate::state_machine::StateMachine;
     use crate::state_machine::StepResult::Terminated;
 
@@ -108,28 +131,40 @@ mod test {
         assert_eq!(machine.downcast_state::<RedMessageState>(), Some(&RedMessageState { count: 0 }));
 
         // Send a message before 0 after 1
-        sender.send(SimpleMessage::IncrementRed { machine_id: "one".to_string() });
+        let _ = sender.send(SimpleMessage::IncrementRed { machine_id: "one".to_string() });
         let _ = machine.step();
         assert_eq!(machine.downcast_state::<RedMessageState>(), Some(&RedMessageState { count: 1 }));
 
         // Send a message before 1 after 2
-        sender.send(SimpleMessage::IncrementRed { machine_id: "two".to_string() });
+        let _ = sender.send(SimpleMessage::IncrementRed { machine_id: "two".to_string() });
         let _ = machine.step();
         assert_eq!(machine.downcast_state::<RedMessageState>(), Some(&RedMessageState { count: 2 }));
 
         // Send a message before 2 after 3
-        sender.send(SimpleMessage::IncrementRed { machine_id: "three".to_string() });
+        let _ = sender.send(SimpleMessage::IncrementRed { machine_id: "three".to_string() });
         let _ = machine.step();
         assert_eq!(machine.downcast_state::<BlueMessageState>(), Some(&BlueMessageState { count: 0 }));
 
         // Send another message and step the machine. before step : 2
-        sender.send(SimpleMessage::IncrementBlue { machine_id: "one".to_string() });
+        let _ = sender.send(SimpleMessage::IncrementBlue { machine_id: "one".to_string() });
         let _ = machine.step();
         assert_eq!(machine.downcast_state::<BlueMessageState>(), Some(&BlueMessageState { count: 1 }));
 
-        sender.send(SimpleMessage::IncrementBlue { machine_id: "two".to_string() });
+        let _ = sender.send(SimpleMessage::IncrementBlue { machine_id: "two".to_string() });
         let result = machine.step();
         assert_eq!(machine.downcast_state::<BlueMessageState>(), Some(&BlueMessageState { count: 2 }));
         assert_eq!(result, Ok(Terminated));
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn it_reports_a_dropped_machine() {
+        let (sender, _) = create_channel();
+        let (machine, sender) = StateMachine::new("simple".to_string(), sender, Box::new(RedMessageState::new()));
+        assert!(!sender.is_closed());
+
+        drop(machine);
+        let message = SimpleMessage::IncrementRed { machine_id: "simple".to_string() };
+        assert!(sender.is_closed());
+        assert!(matches!(sender.send(message), Err(SendError::Disconnected(_))));
+    }
+}
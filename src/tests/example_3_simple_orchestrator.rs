@@ -1,13 +1,17 @@
 #[cfg(test)]
 mod test {
     use std::cell::RefCell;
-    use std::char::ToUppercase;
     use std::rc::Rc;
 
+    use crate::clock::ManualClock;
+    use crate::id_generator::{IdGenerator, MonotonicIdGenerator};
+    use crate::message_channel::{OverflowPolicy, SendError};
     use crate::state::{DeliveryStatus, NoMessage, State, StateMachineMessage, StateType, Transition};
     use crate::state::DeliveryStatus::Delivered;
     use crate::state::Transition::{Same, Terminal};
-    use crate::state_machine_orchestrator::{SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::state_machine::{MachineStatus, StableResult, StateMachineId, StepResult};
+    use crate::state_machine_orchestrator::{BudgetedStepReport, OrchestratorError, SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::support::SteadyInstructionCounter;
 
     #[derive(Debug, PartialEq)]
     pub struct Red {
@@ -28,6 +32,7 @@ mod test {
         machine_id: String,
     }
 
+    #[allow(clippy::enum_variant_names)]
     #[derive(Clone, Debug, PartialEq)]
     pub enum Commands {
         StartFoo { id: String },
@@ -74,7 +79,7 @@ mod test {
     }
 
     impl State<Types> for Red {
-        fn deliver(&mut self, message: Message) -> DeliveryStatus<Message, String> {
+        fn deliver(&mut self, _message: Message) -> DeliveryStatus<Message, String> {
             self.count += 1;
             Delivered
         }
@@ -89,7 +94,7 @@ mod test {
             vec![Commands::StartFoo { id: "".to_string() }]
         }
 
-        fn deliver(&mut self, message: Message) -> DeliveryStatus<Message, String> {
+        fn deliver(&mut self, _message: Message) -> DeliveryStatus<Message, String> {
             Delivered
         }
 
@@ -103,7 +108,7 @@ mod test {
             vec![Commands::StartBar { id: "".to_string() }]
         }
 
-        fn deliver(&mut self, message: Message) -> DeliveryStatus<Message, String> {
+        fn deliver(&mut self, _message: Message) -> DeliveryStatus<Message, String> {
             Delivered
         }
 
@@ -117,7 +122,7 @@ mod test {
             vec![Commands::StartBaz { id: "".to_string() }]
         }
 
-        fn deliver(&mut self, message: Message) -> DeliveryStatus<Message, String> {
+        fn deliver(&mut self, _message: Message) -> DeliveryStatus<Message, String> {
             Delivered
         }
 
@@ -132,30 +137,35 @@ mod test {
 
         let (id_one, _) = orchestrator.create_machine(
             Box::new(Red { count: 0 })
-        );
+        ).unwrap();
         let (id_static, _) = orchestrator.create_machine(
             Box::new(Red { count: 0 })
-        );
+        ).unwrap();
 
         orchestrator.handle_message(
             Message { machine_id: id_one.clone() }
-        );
+        ).unwrap();
         orchestrator.handle_message(
             Message { machine_id: id_one.clone() }
-        );
+        ).unwrap();
 
         let machine_one = orchestrator.get_state_machine(&id_one).unwrap();
         let machine_static = orchestrator.get_state_machine(&id_static).unwrap();
 
         assert_eq!(machine_one.downcast_state::<Red>().unwrap().count, 2);
         assert_eq!(machine_static.downcast_state::<Red>().unwrap().count, 0);
+
+        assert_eq!(
+            orchestrator.handle_message(Message { machine_id: "missing".to_string() }),
+            Err(OrchestratorError::MachineNotFound("missing".to_string()))
+        );
     }
 
     #[test]
     pub fn it_routes_passes_commands() {
-        let mut commands = Rc::new(RefCell::new(vec![]));
+        let commands = Rc::new(RefCell::new(vec![]));
 
-        let mut handler_commands = commands.clone();
+        let handler_commands = commands.clone();
         let handler = move |v: Commands| {
             handler_commands.borrow_mut().push(v);
         };
@@ -166,22 +176,234 @@ mod test {
 
         let (id_one, _) = orchestrator.create_machine(
             Box::new(CommandStageOne {})
-        );
+        ).unwrap();
 
-        orchestrator.step_machine(&id_one);
+        orchestrator.step_machine(&id_one).unwrap();
 
         assert_eq!(commands.borrow_mut().len(), 1);
         assert_eq!(commands.borrow_mut().pop(), Some(Commands::StartFoo { id: "".to_string() }));
 
-        orchestrator.step_machine(&id_one);
+        orchestrator.step_machine(&id_one).unwrap();
 
         assert_eq!(commands.borrow_mut().len(), 1);
         assert_eq!(commands.borrow_mut().pop(), Some(Commands::StartBar { id: "".to_string() }));
 
 
-        orchestrator.step_machine(&id_one);
+        orchestrator.step_machine(&id_one).unwrap();
 
         assert_eq!(commands.borrow_mut().len(), 1);
         assert_eq!(commands.borrow_mut().pop(), Some(Commands::StartBaz { id: "".to_string() }));
     }
-}
\ No newline at end of file
+
+    #[test]
+    pub fn it_bounds_inbound_channels() {
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(|_| {}));
+        orchestrator.set_inbound_capacity(2, OverflowPolicy::Reject);
+
+        let (id_one, handle) = orchestrator.create_machine(
+            Box::new(Red { count: 0 })
+        ).unwrap();
+
+        handle.send(Message { machine_id: id_one.clone() }).unwrap();
+        handle.send(Message { machine_id: id_one.clone() }).unwrap();
+        assert_eq!(handle.capacity(), Some(2));
+        assert_eq!(handle.pending(), 2);
+        assert_eq!(handle.send(Message { machine_id: id_one.clone() }), Err(SendError::Full(Message { machine_id: id_one.clone() })));
+        assert_eq!(orchestrator.handle_message(Message { machine_id: id_one.clone() }), Err(OrchestratorError::QueueFull(id_one.clone())));
+
+        orchestrator.step_machine(&id_one).unwrap();
+        assert_eq!(handle.pending(), 0);
+
+        let machine_one = orchestrator.get_state_machine(&id_one).unwrap();
+        assert_eq!(machine_one.downcast_state::<Red>().unwrap().count, 2);
+    }
+
+    #[test]
+    pub fn it_returns_batch_messages_that_did_not_fit() {
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(|_| {}));
+        orchestrator.set_inbound_capacity(2, OverflowPolicy::Reject);
+        let (id_one, _) = orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap();
+
+        let report = orchestrator.handle_messages((0..3).map(|_| Message { machine_id: id_one.clone() }).collect());
+        assert_eq!(report.results, vec![(id_one.clone(), Err(OrchestratorError::QueueFull(id_one.clone())))]);
+        assert_eq!(report.unsent, vec![Message { machine_id: id_one.clone() }]);
+
+        // The queued part of the batch was handled, the rest can be sent again
+        let report = orchestrator.handle_messages(report.unsent);
+        assert_eq!(report.results, vec![(id_one.clone(), Ok(StepResult::Running))]);
+        assert_eq!(orchestrator.get_state_machine(&id_one).unwrap().downcast_state::<Red>().unwrap().count, 3);
+    }
+
+    #[test]
+    pub fn it_steps_each_machine_once_per_batch() {
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(|_| {}));
+
+        let (id_one, _) = orchestrator.create_machine(
+            Box::new(Red { count: 0 })
+        ).unwrap();
+        let (id_two, _) = orchestrator.create_machine(
+            Box::new(Red { count: 0 })
+        ).unwrap();
+
+        let report = orchestrator.handle_messages(vec![
+            Message { machine_id: id_two.clone() },
+            Message { machine_id: id_one.clone() },
+            Message { machine_id: "missing".to_string() },
+            Message { machine_id: id_two.clone() },
+            Message { machine_id: id_two.clone() },
+        ]);
+
+        assert!(report.unsent.is_empty());
+        assert_eq!(report.results, vec![
+            (id_two.clone(), Ok(StepResult::Running)),
+            (id_one.clone(), Ok(StepResult::Running)),
+            ("missing".to_string(), Err(OrchestratorError::MachineNotFound("missing".to_string()))),
+        ]);
+
+        let machine_one = orchestrator.get_state_machine(&id_one).unwrap();
+        let machine_two = orchestrator.get_state_machine(&id_two).unwrap();
+        assert_eq!(machine_one.downcast_state::<Red>().unwrap().count, 1);
+        assert_eq!(machine_two.downcast_state::<Red>().unwrap().count, 3);
+        assert_eq!(machine_one.steps(), 1);
+        assert_eq!(machine_two.steps(), 1);
+    }
+
+    #[test]
+    pub fn it_creates_machines_with_chosen_ids() {
+        let mut orchestrator = SimpleMachineOrchestrator::<Types>::new(Box::new(|_| {}));
+        orchestrator.set_id_generator(Box::new(MonotonicIdGenerator::starting_at(5)));
+
+        let (chosen, _) = orchestrator.create_machine_with_id("6".to_string(), Box::new(Red { count: 0 })).unwrap();
+        let duplicate = orchestrator.create_machine_with_id(chosen.clone(), Box::new(Red { count: 0 }));
+        assert!(matches!(duplicate, Err(OrchestratorError::DuplicateId(id)) if id == chosen));
+
+        // Generated ids skip the one already taken
+        assert_eq!(orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap().0, "5");
+        assert_eq!(orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap().0, "7");
+
+        // The counter survives an upgrade
+        let state = orchestrator.export_id_generator_state().unwrap();
+        let mut upgraded = SimpleMachineOrchestrator::<Types>::new(Box::new(|_| {}));
+        upgraded.restore_id_generator_state(&state).unwrap();
+        assert_eq!(upgraded.create_machine(Box::new(Red { count: 0 })).unwrap().0, "8");
+    }
+
+    struct ConstantIdGenerator;
+
+    impl IdGenerator for ConstantIdGenerator {
+        fn generate(&mut self) -> StateMachineId {
+            "same".to_string()
+        }
+    }
+
+    #[test]
+    pub fn it_gives_up_when_generated_ids_are_taken() {
+        let mut orchestrator = SimpleMachineOrchestrator::<Types>::new(Box::new(|_| {}));
+        orchestrator.set_id_generator(Box::new(ConstantIdGenerator));
+
+        orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap();
+        assert!(matches!(orchestrator.create_machine(Box::new(Red { count: 0 })), Err(OrchestratorError::IdGeneratorExhausted)));
+    }
+
+    #[test]
+    pub fn it_steps_machines_within_an_instruction_budget() {
+        let counter = SteadyInstructionCounter::default();
+        let mut orchestrator = SimpleMachineOrchestrator::<Types>::new(Box::new(|_| {}));
+        orchestrator.set_instruction_counter(Rc::new(counter.clone()));
+        let ids: Vec<String> = (0..5).map(|_| orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap().0).collect();
+        let steps = |orchestrator: &SimpleMachineOrchestrator<Types>| -> Vec<u64> {
+            ids.iter().map(|id| orchestrator.get_state_machine(id).unwrap().steps()).collect()
+        };
+
+        assert_eq!(orchestrator.step_machines_within(750), BudgetedStepReport { stepped: 3, budget_exhausted: true });
+        assert_eq!(steps(&orchestrator), vec![1, 1, 1, 0, 0]);
+
+        // A new message starts counting from zero again, and stepping resumes where it stopped
+        counter.reset();
+        assert_eq!(orchestrator.step_machines_within(750), BudgetedStepReport { stepped: 3, budget_exhausted: true });
+        assert_eq!(steps(&orchestrator), vec![2, 1, 1, 1, 1]);
+
+        counter.reset();
+        assert_eq!(orchestrator.step_machines_within(10_000), BudgetedStepReport { stepped: 5, budget_exhausted: false });
+        assert_eq!(steps(&orchestrator), vec![3, 2, 2, 2, 2]);
+    }
+
+    #[test]
+    pub fn it_steps_only_ready_machines() {
+        let clock = ManualClock::new(0);
+        let mut orchestrator = SimpleMachineOrchestrator::<Types>::new(Box::new(|_| {}));
+        orchestrator.set_clock(Rc::new(clock.clone()));
+        let (id_one, handle_one) = orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap();
+        let (id_two, _) = orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap();
+
+        // New machines are ready to be initialized
+        assert_eq!(orchestrator.step_ready_machines().len(), 2);
+        assert!(orchestrator.step_ready_machines().is_empty());
+
+        handle_one.send(Message { machine_id: id_one.clone() }).unwrap();
+        assert_eq!(orchestrator.step_ready_machines(), vec![(id_one.clone(), Ok(StepResult::Running))]);
+
+        orchestrator.wake(&id_two).unwrap();
+        assert_eq!(orchestrator.ready_machines(), vec![id_two.clone()]);
+        orchestrator.step_ready_machines();
+        assert_eq!(orchestrator.wake(&"missing".to_string()), Err(OrchestratorError::MachineNotFound("missing".to_string())));
+
+        orchestrator.wake_at(&id_two, 100).unwrap();
+        assert!(orchestrator.step_ready_machines().is_empty());
+        clock.advance(100);
+        assert_eq!(orchestrator.step_ready_machines(), vec![(id_two.clone(), Ok(StepResult::Running))]);
+
+        // Messages handled by the orchestrator don't leave the machine ready
+        orchestrator.handle_message(Message { machine_id: id_one.clone() }).unwrap();
+        assert!(orchestrator.ready_machines().is_empty());
+        assert_eq!(orchestrator.get_state_machine(&id_one).unwrap().downcast_state::<Red>().unwrap().count, 2);
+    }
+
+    #[test]
+    pub fn it_keeps_transitioned_machines_ready() {
+        let mut orchestrator = SimpleMachineOrchestrator::<TypesWithCommands>::new(Box::new(|_| {}));
+        let (id_one, _) = orchestrator.create_machine(Box::new(CommandStageOne {})).unwrap();
+
+        assert_eq!(orchestrator.step_ready_machines(), vec![(id_one.clone(), Ok(StepResult::Running))]);
+        assert_eq!(orchestrator.step_ready_machines(), vec![(id_one.clone(), Ok(StepResult::Running))]);
+        assert_eq!(orchestrator.step_ready_machines(), vec![(id_one.clone(), Ok(StepResult::Terminated))]);
+        assert!(orchestrator.step_ready_machines().is_empty());
+    }
+
+    #[test]
+    pub fn it_steps_machines_to_completion() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let handler_commands = commands.clone();
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(move |command| handler_commands.borrow_mut().push(command)));
+        let (id_one, _) = orchestrator.create_machine(Box::new(CommandStageOne {})).unwrap();
+
+        assert_eq!(orchestrator.step_until_stable(&id_one, 10), Ok(StableResult::Terminated));
+        assert_eq!(commands.borrow().len(), 3);
+        assert_eq!(orchestrator.get_state_machine(&id_one).unwrap().steps(), 3);
+
+        let mut orchestrator = SimpleMachineOrchestrator::<Types>::new(Box::new(|_| {}));
+        let (id_two, _) = orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap();
+        assert_eq!(orchestrator.step_until_stable(&id_two, 10), Ok(StableResult::Stable));
+        assert_eq!(orchestrator.get_state_machine(&id_two).unwrap().steps(), 1);
+    }
+
+    #[test]
+    pub fn it_buffers_messages_for_paused_machines() {
+        let mut orchestrator = SimpleMachineOrchestrator::<Types>::new(Box::new(|_| {}));
+        let (id_one, handle) = orchestrator.create_machine(Box::new(Red { count: 0 })).unwrap();
+        orchestrator.step_ready_machines();
+
+        orchestrator.pause(&id_one).unwrap();
+        assert!(matches!(orchestrator.pause(&id_one), Err(OrchestratorError::NotRunning(_, MachineStatus::Paused))));
+        assert_eq!(orchestrator.handle_message(Message { machine_id: id_one.clone() }), Ok(StepResult::Paused));
+        assert!(orchestrator.step_ready_machines().is_empty());
+        orchestrator.step_all_machines();
+        assert_eq!(handle.pending(), 1);
+
+        orchestrator.resume(&id_one).unwrap();
+        assert!(matches!(orchestrator.resume(&id_one), Err(OrchestratorError::NotPaused(_, MachineStatus::Running))));
+        assert_eq!(orchestrator.step_ready_machines(), vec![(id_one.clone(), Ok(StepResult::Running))]);
+        assert_eq!(handle.pending(), 0);
+        assert_eq!(orchestrator.get_state_machine(&id_one).unwrap().downcast_state::<Red>().unwrap().count, 1);
+    }
+}
@@ -0,0 +1,132 @@
+use crate::state::{DeliveryStatus, NoMessage, State, StateMachineMessage, StateType, Transition};
+
+// A worker that applies updates until it is cancelled. Cancellation jumps the queue.
+// Aborting uses the built-in cancel message instead, stopping the worker without delivering anything.
+
+#[derive(Debug, PartialEq)]
+pub struct Working {
+    pub applied: Vec<u64>,
+    pub cancelled: bool,
+}
+
+#[derive(Clone, Debug)]
+pub enum WorkerMessage {
+    Update { machine_id: String, value: u64 },
+    Cancel { machine_id: String },
+    Abort { machine_id: String, reason: String },
+}
+
+impl StateMachineMessage for WorkerMessage {
+    fn id(&self) -> &String {
+        match self {
+            WorkerMessage::Update { machine_id, .. } => machine_id,
+            WorkerMessage::Cancel { machine_id } => machine_id,
+            WorkerMessage::Abort { machine_id, .. } => machine_id,
+        }
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+
+    fn priority(&self) -> u8 {
+        match self {
+            WorkerMessage::Update { .. } => 0,
+            WorkerMessage::Cancel { .. } => 10,
+            WorkerMessage::Abort { .. } => 0,
+        }
+    }
+
+    fn cancellation(&self) -> Option<String> {
+        match self {
+            WorkerMessage::Abort { reason, .. } => Some(reason.clone()),
+            _ => None,
+        }
+    }
+}
+
+pub struct MachineTypes {}
+
+impl StateType for MachineTypes {
+    type In = WorkerMessage;
+    type Out = NoMessage;
+}
+
+impl State<MachineTypes> for Working {
+    fn deliver(&mut self, message: WorkerMessage) -> DeliveryStatus<WorkerMessage, String> {
+        match message {
+            // Updates that arrive after cancellation are ignored
+            WorkerMessage::Update { value, .. } if !self.cancelled => self.applied.push(value),
+            WorkerMessage::Update { .. } => {}
+            WorkerMessage::Cancel { .. } => self.cancelled = true,
+            // Cancels the machine, it is never delivered
+            WorkerMessage::Abort { .. } => {}
+        }
+        DeliveryStatus::Delivered
+    }
+
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        if self.cancelled {
+            return Ok(Transition::Terminal);
+        }
+        Ok(Transition::Same)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::message_channel::create_channel;
+    use crate::state_machine::{MachineStatus, StateMachine};
+    use crate::state_machine::StepResult::{Cancelled, Running, Terminated};
+    use crate::tests::example_4_priority_messages::{WorkerMessage, Working};
+
+    fn update(value: u64) -> WorkerMessage {
+        WorkerMessage::Update { machine_id: "worker".to_string(), value }
+    }
+
+    fn cancel() -> WorkerMessage {
+        WorkerMessage::Cancel { machine_id: "worker".to_string() }
+    }
+
+    #[test]
+    pub fn it_delivers_updates_in_order() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("worker".to_string(), sender, Box::new(Working { applied: vec![], cancelled: false }));
+
+        for value in 1..=3 {
+            handle.send(update(value)).unwrap();
+        }
+
+        assert_eq!(machine.step(), Ok(Running));
+        assert_eq!(machine.downcast_state::<Working>().unwrap().applied, vec![1, 2, 3]);
+    }
+
+    #[test]
+    pub fn it_delivers_cancellation_before_queued_updates() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("worker".to_string(), sender, Box::new(Working { applied: vec![], cancelled: false }));
+
+        handle.send(update(1)).unwrap();
+        handle.send(update(2)).unwrap();
+        handle.send(cancel()).unwrap();
+        handle.send(update(3)).unwrap();
+
+        assert_eq!(machine.step(), Ok(Terminated));
+        assert_eq!(machine.downcast_state::<Working>(), Some(&Working { applied: vec![], cancelled: true }));
+    }
+
+    #[test]
+    pub fn it_cancels_the_machine_on_cancel_messages() {
+        let (sender, _) = create_channel();
+        let (mut machine, handle) = StateMachine::new("worker".to_string(), sender, Box::new(Working { applied: vec![], cancelled: false }));
+
+        handle.send(update(1)).unwrap();
+        handle.send(WorkerMessage::Abort { machine_id: "worker".to_string(), reason: "shutdown".to_string() }).unwrap();
+        handle.send(update(2)).unwrap();
+
+        assert_eq!(machine.step(), Ok(Cancelled));
+        assert_eq!(machine.status(), &MachineStatus::Cancelled { reason: "shutdown".to_string() });
+        assert_eq!(machine.downcast_state::<Working>().unwrap().applied, Vec::<u64>::new());
+        assert_eq!(machine.steps(), 0);
+    }
+}
@@ -0,0 +1,121 @@
+use crate::state::{DeliveryStatus, NoMessage, State, StateMachineMessage, StateType, Transition};
+
+// Orders track the latest price until they are shipped.
+// Price updates are broadcast to every order, so their machine id is not used.
+
+#[derive(Debug, PartialEq)]
+pub struct AwaitingPayment {
+    pub price: u64,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Shipped {}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderMessage {
+    PriceUpdate { machine_id: String, price: u64 },
+}
+
+impl StateMachineMessage for OrderMessage {
+    fn id(&self) -> &String {
+        match self {
+            OrderMessage::PriceUpdate { machine_id, .. } => machine_id,
+        }
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+}
+
+pub struct MachineTypes {}
+
+impl StateType for MachineTypes {
+    type In = OrderMessage;
+    type Out = NoMessage;
+}
+
+impl State<MachineTypes> for AwaitingPayment {
+    fn deliver(&mut self, message: OrderMessage) -> DeliveryStatus<OrderMessage, String> {
+        let OrderMessage::PriceUpdate { price, .. } = message;
+        self.price = price;
+        DeliveryStatus::Delivered
+    }
+
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        Ok(Transition::Same)
+    }
+}
+
+impl State<MachineTypes> for Shipped {
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        Ok(Transition::Same)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state_machine_orchestrator::{OrchestratorError, SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::state_machine::StateMachineError;
+    use crate::tests::example_5_broadcast::{AwaitingPayment, MachineTypes, OrderMessage, Shipped};
+
+    fn orchestrator() -> (SimpleMachineOrchestrator<MachineTypes>, String, String, String) {
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(|_| {}));
+        let (one, _) = orchestrator.create_machine(Box::new(AwaitingPayment { price: 0 })).unwrap();
+        let (two, _) = orchestrator.create_machine(Box::new(AwaitingPayment { price: 0 })).unwrap();
+        let (shipped, _) = orchestrator.create_machine(Box::new(Shipped {})).unwrap();
+        (orchestrator, one, two, shipped)
+    }
+
+    fn update(price: u64) -> OrderMessage {
+        OrderMessage::PriceUpdate { machine_id: String::new(), price }
+    }
+
+    fn price(orchestrator: &SimpleMachineOrchestrator<MachineTypes>, machine_id: &String) -> u64 {
+        orchestrator.get_state_machine(machine_id).unwrap().downcast_state::<AwaitingPayment>().unwrap().price
+    }
+
+    #[test]
+    pub fn it_broadcasts_to_all_machines() {
+        let (mut orchestrator, one, two, shipped) = orchestrator();
+
+        let report = orchestrator.broadcast(update(5));
+
+        assert_eq!(report.accepted, vec![one.clone(), two.clone()]);
+        assert_eq!(report.rejected.len(), 1);
+        assert_eq!(report.rejected[0].0, shipped);
+        assert!(matches!(report.rejected[0].1, OrchestratorError::StepFailed(_, StateMachineError { .. })));
+        assert_eq!(price(&orchestrator, &one), 5);
+        assert_eq!(price(&orchestrator, &two), 5);
+    }
+
+    #[test]
+    pub fn it_multicasts_by_state_name() {
+        let (mut orchestrator, one, two, _) = orchestrator();
+
+        let report = orchestrator.multicast_to_state("AwaitingPayment", update(7));
+
+        assert_eq!(report.accepted, vec![one, two]);
+        assert!(report.rejected.is_empty());
+    }
+
+    #[test]
+    pub fn it_multicasts_by_tag() {
+        let (mut orchestrator, one, two, shipped) = orchestrator();
+        orchestrator.tag_machine(&two, "eu").unwrap();
+        orchestrator.tag_machine(&shipped, "eu").unwrap();
+        orchestrator.tag_machine(&one, "us").unwrap();
+        assert_eq!(orchestrator.tag_machine(&"missing".to_string(), "eu"), Err(OrchestratorError::MachineNotFound("missing".to_string())));
+        assert_eq!(orchestrator.machine_tags(&two), vec!["eu".to_string()]);
+
+        let report = orchestrator.multicast_to_tag("eu", update(9));
+
+        assert_eq!(report.accepted, vec![two.clone()]);
+        assert_eq!(report.rejected.iter().map(|(id, _)| id.clone()).collect::<Vec<_>>(), vec![shipped]);
+        assert_eq!(price(&orchestrator, &one), 0);
+        assert_eq!(price(&orchestrator, &two), 9);
+
+        orchestrator.untag_machine(&two, "eu");
+        assert!(orchestrator.machine_tags(&two).is_empty());
+    }
+}
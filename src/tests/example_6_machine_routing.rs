@@ -0,0 +1,139 @@
+use crate::state::{DeliveryStatus, State, StateMachineMessage, StateType, Transition};
+
+// A chain of relays. Each relay passes a hop on to the next one when it is first stepped,
+// and the last relay reports to the command handler.
+
+#[derive(Debug, PartialEq)]
+pub struct Relay {
+    pub next: Option<String>,
+    pub id: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Hop {
+    pub machine_id: String,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RelayCommand {
+    Forward(Hop),
+    Report { id: String },
+}
+
+impl StateMachineMessage for Hop {
+    fn id(&self) -> &String {
+        &self.machine_id
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+}
+
+impl StateMachineMessage for RelayCommand {
+    fn id(&self) -> &String {
+        match self {
+            RelayCommand::Forward(hop) => hop.id(),
+            RelayCommand::Report { id } => id,
+        }
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+}
+
+pub struct MachineTypes {}
+
+impl StateType for MachineTypes {
+    type In = Hop;
+    type Out = RelayCommand;
+}
+
+impl State<MachineTypes> for Relay {
+    fn initialize(&self) -> Vec<RelayCommand> {
+        match &self.next {
+            Some(next) => vec![RelayCommand::Forward(Hop { machine_id: next.clone() })],
+            None => vec![RelayCommand::Report { id: self.id.clone() }],
+        }
+    }
+
+    fn deliver(&mut self, _message: Hop) -> DeliveryStatus<Hop, String> {
+        DeliveryStatus::Delivered
+    }
+
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        Ok(Transition::Same)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::state_machine_orchestrator::{SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::example_6_machine_routing::{MachineTypes, Relay, RelayCommand};
+    use crate::tests::support::SteadyInstructionCounter;
+
+    // Relays "0".."count - 1", each forwarding to the next
+    fn chain(count: u64, reports: Rc<RefCell<Vec<RelayCommand>>>) -> SimpleMachineOrchestrator<MachineTypes> {
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(move |command| reports.borrow_mut().push(command)));
+        orchestrator.set_router(Box::new(|command| match command {
+            RelayCommand::Forward(hop) => Ok(hop),
+            command => Err(command),
+        }));
+
+        for index in 0..count {
+            let next = if index + 1 < count { Some((index + 1).to_string()) } else { None };
+            orchestrator.create_machine(Box::new(Relay { next, id: index.to_string() })).unwrap();
+        }
+        orchestrator
+    }
+
+    #[test]
+    pub fn it_routes_messages_between_machines_in_one_round() {
+        let reports = Rc::new(RefCell::new(vec![]));
+        let mut orchestrator = chain(4, reports.clone());
+
+        orchestrator.step_machine("0").unwrap();
+
+        for id in ["0", "1", "2", "3"] {
+            assert_eq!(orchestrator.get_state_machine(&id.to_string()).unwrap().steps(), 1);
+        }
+        assert_eq!(*reports.borrow(), vec![RelayCommand::Report { id: "3".to_string() }]);
+    }
+
+    #[test]
+    pub fn it_queues_messages_beyond_max_hops() {
+        let reports = Rc::new(RefCell::new(vec![]));
+        let mut orchestrator = chain(4, reports.clone());
+        orchestrator.set_max_hops(2);
+
+        orchestrator.step_machine("0").unwrap();
+
+        let last = "3".to_string();
+        assert_eq!(orchestrator.get_state_machine(&"2".to_string()).unwrap().steps(), 1);
+        assert_eq!(orchestrator.get_state_machine(&last).unwrap().steps(), 0);
+        assert!(reports.borrow().is_empty());
+
+        // The queued hop is delivered on the next step
+        orchestrator.step_machine(&last).unwrap();
+        assert_eq!(*reports.borrow(), vec![RelayCommand::Report { id: "3".to_string() }]);
+    }
+
+    #[test]
+    pub fn it_queues_routed_messages_once_the_budget_is_spent() {
+        let reports = Rc::new(RefCell::new(vec![]));
+        let mut orchestrator = chain(4, reports.clone());
+        orchestrator.set_instruction_counter(Rc::new(SteadyInstructionCounter::default()));
+
+        let report = orchestrator.step_machines_within(450);
+        assert_eq!(report.stepped, 1);
+
+        let next = "1".to_string();
+        assert_eq!(orchestrator.get_state_machine(&next).unwrap().steps(), 0);
+        assert_eq!(orchestrator.ready_machines(), vec!["1", "2", "3"]);
+        assert!(reports.borrow().is_empty());
+    }
+}
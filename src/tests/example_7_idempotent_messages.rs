@@ -0,0 +1,151 @@
+use crate::state::{DeliveryStatus, NoMessage, State, StateMachineMessage, StateType, Transition};
+
+// A counter incremented by client requests. Clients retry requests with the same request id,
+// which must only be counted once.
+
+#[derive(Debug, PartialEq)]
+pub struct Counter {
+    pub count: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Increment {
+    pub machine_id: String,
+    pub request_id: String,
+}
+
+impl StateMachineMessage for Increment {
+    fn id(&self) -> &String {
+        &self.machine_id
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+
+    fn idempotency_key(&self) -> Option<String> {
+        Some(self.request_id.clone())
+    }
+}
+
+pub struct MachineTypes {}
+
+impl StateType for MachineTypes {
+    type In = Increment;
+    type Out = NoMessage;
+}
+
+impl State<MachineTypes> for Counter {
+    fn deliver(&mut self, _message: Increment) -> DeliveryStatus<Increment, String> {
+        self.count += 1;
+        DeliveryStatus::Delivered
+    }
+
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        if self.count > 2 {
+            return Err("Counted too far".to_string());
+        }
+        Ok(Transition::Same)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::rc::Rc;
+
+    use crate::clock::ManualClock;
+    use crate::message_channel::OverflowPolicy;
+    use crate::state_machine::StepResult;
+    use crate::state_machine_orchestrator::{OrchestratorError, SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::example_7_idempotent_messages::{Counter, Increment, MachineTypes};
+
+    fn increment(machine_id: &str, request_id: &str) -> Increment {
+        Increment { machine_id: machine_id.to_string(), request_id: request_id.to_string() }
+    }
+
+    fn count(orchestrator: &SimpleMachineOrchestrator<MachineTypes>, machine_id: &String) -> u64 {
+        orchestrator.get_state_machine(machine_id).unwrap().downcast_state::<Counter>().unwrap().count
+    }
+
+    #[test]
+    pub fn it_handles_retried_messages_once() {
+        let clock = ManualClock::new(0);
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(|_| {}));
+        orchestrator.set_clock(Rc::new(clock.clone()));
+        orchestrator.enable_deduplication(10, 100);
+        let (id, _) = orchestrator.create_machine(Box::new(Counter { count: 0 })).unwrap();
+
+        assert_eq!(orchestrator.handle_message(increment(&id, "a")), Ok(StepResult::Running));
+        assert_eq!(orchestrator.handle_message(increment(&id, "a")), Ok(StepResult::Running));
+        assert_eq!(count(&orchestrator, &id), 1);
+
+        // Failed outcomes are replayed too
+        orchestrator.handle_message(increment(&id, "b")).unwrap();
+        let failed = orchestrator.handle_message(increment(&id, "c"));
+        assert!(matches!(failed, Err(OrchestratorError::StepFailed(_, _))));
+        assert_eq!(orchestrator.handle_message(increment(&id, "c")), failed);
+        assert_eq!(count(&orchestrator, &id), 3);
+
+        // Once the window passed the message is handled again
+        clock.advance(100);
+        assert!(orchestrator.handle_message(increment(&id, "a")).is_err());
+        assert_eq!(count(&orchestrator, &id), 4);
+    }
+
+    #[test]
+    pub fn it_deduplicates_batches() {
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(|_| {}));
+        orchestrator.set_clock(Rc::new(ManualClock::new(0)));
+        orchestrator.enable_deduplication(10, 100);
+        let (one, _) = orchestrator.create_machine(Box::new(Counter { count: 0 })).unwrap();
+        let (two, _) = orchestrator.create_machine(Box::new(Counter { count: 0 })).unwrap();
+
+        orchestrator.handle_message(increment(&one, "a")).unwrap();
+        let results = orchestrator.handle_messages(vec![increment(&one, "a"), increment(&two, "b"), increment(&two, "c"), increment(&two, "c")]);
+        assert_eq!(results.results, vec![(two.clone(), Ok(StepResult::Running)), (one.clone(), Ok(StepResult::Running))]);
+        // The retry of "c" within the batch is answered with the outcome of the first "c"
+        assert_eq!(results.duplicates, vec![
+            (one.clone(), "a".to_string(), Ok(StepResult::Running)),
+            (two.clone(), "c".to_string(), Ok(StepResult::Running)),
+        ]);
+
+        let results = orchestrator.handle_messages(vec![increment(&two, "b"), increment(&two, "c")]);
+        assert_eq!(results.duplicates.len(), 2);
+        assert_eq!(count(&orchestrator, &one), 1);
+        assert_eq!(count(&orchestrator, &two), 2);
+    }
+
+    #[test]
+    pub fn it_scopes_keys_to_machines_and_remembers_queued_messages_of_a_full_batch() {
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(|_| {}));
+        orchestrator.set_clock(Rc::new(ManualClock::new(0)));
+        orchestrator.set_inbound_capacity(2, OverflowPolicy::Reject);
+        orchestrator.enable_deduplication(10, 100);
+        let (one, _) = orchestrator.create_machine(Box::new(Counter { count: 0 })).unwrap();
+        let (two, _) = orchestrator.create_machine(Box::new(Counter { count: 0 })).unwrap();
+
+        // The same request id sent to different machines is counted by each
+        orchestrator.handle_message(increment(&one, "a")).unwrap();
+        orchestrator.handle_message(increment(&two, "a")).unwrap();
+        assert_eq!(count(&orchestrator, &two), 1);
+
+        let report = orchestrator.handle_messages(vec![increment(&one, "b"), increment(&one, "c"), increment(&one, "d")]);
+        assert_eq!(report.unsent, vec![increment(&one, "d")]);
+        assert_eq!(count(&orchestrator, &one), 3);
+
+        // Retrying the whole batch only handles the message that did not fit
+        let report = orchestrator.handle_messages(vec![increment(&one, "b"), increment(&one, "c"), increment(&one, "d")]);
+        assert!(report.unsent.is_empty());
+        assert_eq!(count(&orchestrator, &one), 4);
+    }
+
+    #[test]
+    pub fn it_handles_every_message_without_deduplication() {
+        let mut orchestrator = SimpleMachineOrchestrator::new(Box::new(|_| {}));
+        let (id, _) = orchestrator.create_machine(Box::new(Counter { count: 0 })).unwrap();
+
+        orchestrator.handle_message(increment(&id, "a")).unwrap();
+        orchestrator.handle_message(increment(&id, "a")).unwrap();
+        assert_eq!(count(&orchestrator, &id), 2);
+    }
+}
@@ -0,0 +1,141 @@
+use crate::state::{DeliveryStatus, State, StateMachineMessage, StateType, Transition};
+
+// An order that waits for payments. Cancelling an order refunds what was paid.
+
+#[derive(Debug, PartialEq)]
+pub struct AwaitingPayment {}
+
+#[derive(Debug, PartialEq)]
+pub struct Paid {
+    pub order_id: String,
+    pub amount: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Payment {
+    pub machine_id: String,
+    pub amount: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum OrderCommand {
+    Refund { order_id: String, amount: u64 },
+}
+
+impl StateMachineMessage for Payment {
+    fn id(&self) -> &String {
+        &self.machine_id
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+}
+
+impl StateMachineMessage for OrderCommand {
+    fn id(&self) -> &String {
+        match self {
+            OrderCommand::Refund { order_id, .. } => order_id,
+        }
+    }
+
+    fn unpack(self) -> Self {
+        self
+    }
+}
+
+pub struct MachineTypes {}
+
+impl StateType for MachineTypes {
+    type In = Payment;
+    type Out = OrderCommand;
+}
+
+impl State<MachineTypes> for AwaitingPayment {
+    fn deliver(&mut self, _message: Payment) -> DeliveryStatus<Payment, String> {
+        DeliveryStatus::Delivered
+    }
+
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        Ok(Transition::Same)
+    }
+}
+
+impl State<MachineTypes> for Paid {
+    fn deliver(&mut self, message: Payment) -> DeliveryStatus<Payment, String> {
+        self.amount += message.amount;
+        DeliveryStatus::Delivered
+    }
+
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        Ok(Transition::Same)
+    }
+
+    fn on_cancel(&self, _reason: &str) -> Vec<OrderCommand> {
+        vec![OrderCommand::Refund { order_id: self.order_id.clone(), amount: self.amount }]
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    use crate::state_machine::MachineStatus;
+    use crate::state_machine_orchestrator::{OrchestratorError, SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::example_8_cancellation::{AwaitingPayment, MachineTypes, OrderCommand, Paid, Payment};
+
+    #[test]
+    pub fn it_cancels_machines_with_compensation() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let handler_commands = commands.clone();
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(move |command| handler_commands.borrow_mut().push(command)));
+        let (id, _) = orchestrator.create_machine_with_id("order".to_string(), Box::new(Paid { order_id: "order".to_string(), amount: 0 })).unwrap();
+        orchestrator.handle_message(Payment { machine_id: id.clone(), amount: 5 }).unwrap();
+
+        orchestrator.cancel(&id, "customer request").unwrap();
+
+        assert_eq!(*commands.borrow(), vec![OrderCommand::Refund { order_id: id.clone(), amount: 5 }]);
+        let machine = orchestrator.get_state_machine(&id).unwrap();
+        assert_eq!(machine.status(), &MachineStatus::Cancelled { reason: "customer request".to_string() });
+
+        // Cancelled machines don't take messages or further cancellations
+        assert!(matches!(orchestrator.handle_message(Payment { machine_id: id.clone(), amount: 5 }), Err(OrchestratorError::NotRunning(_, MachineStatus::Cancelled { .. }))));
+        assert!(matches!(orchestrator.cancel(&id, "again"), Err(OrchestratorError::NotRunning(_, MachineStatus::Cancelled { .. }))));
+        assert_eq!(orchestrator.cancel("missing", "no such order"), Err(OrchestratorError::MachineNotFound("missing".to_string())));
+    }
+
+    #[test]
+    pub fn it_cancels_paused_machines() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let handler_commands = commands.clone();
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(move |command| handler_commands.borrow_mut().push(command)));
+        let (id, _) = orchestrator.create_machine_with_id("order".to_string(), Box::new(Paid { order_id: "order".to_string(), amount: 0 })).unwrap();
+        orchestrator.get_state_machine_mut(&id).unwrap().enable_history(10);
+        orchestrator.handle_message(Payment { machine_id: id.clone(), amount: 5 }).unwrap();
+        orchestrator.pause(&id).unwrap();
+
+        // The payment sent while paused is never delivered, so it is not refunded
+        orchestrator.handle_message(Payment { machine_id: id.clone(), amount: 3 }).unwrap();
+        orchestrator.cancel(&id, "customer request").unwrap();
+
+        assert_eq!(*commands.borrow(), vec![OrderCommand::Refund { order_id: id.clone(), amount: 5 }]);
+        let machine = orchestrator.get_state_machine(&id).unwrap();
+        assert_eq!(machine.status(), &MachineStatus::Cancelled { reason: "customer request".to_string() });
+        assert_eq!(machine.history().unwrap().last().unwrap().cancel_reason, Some("customer request".to_string()));
+        assert!(matches!(orchestrator.resume(&id), Err(OrchestratorError::NotPaused(_, MachineStatus::Cancelled { .. }))));
+    }
+
+    #[test]
+    pub fn it_cancels_without_compensation_by_default() {
+        let commands = Rc::new(RefCell::new(vec![]));
+        let handler_commands = commands.clone();
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(move |command| handler_commands.borrow_mut().push(command)));
+        let (id, _) = orchestrator.create_machine(Box::new(AwaitingPayment {})).unwrap();
+
+        orchestrator.cancel(&id, "timeout").unwrap();
+
+        assert!(commands.borrow().is_empty());
+        assert!(orchestrator.step_ready_machines().is_empty());
+    }
+}
@@ -0,0 +1,140 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::retry::RetryPolicy;
+use crate::state::{NoMessage, State, StateType, Transition};
+
+// A state that calls a flaky service, retrying with backoff until the service answers.
+
+#[derive(Debug)]
+pub struct Calling {
+    pub failures: Rc<Cell<u32>>,
+    pub error: String,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Done {}
+
+pub struct MachineTypes {}
+
+impl StateType for MachineTypes {
+    type In = NoMessage;
+    type Out = NoMessage;
+}
+
+impl State<MachineTypes> for Calling {
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        if self.failures.get() == 0 {
+            return Ok(Transition::Next(Box::new(Done {})));
+        }
+        self.failures.set(self.failures.get() - 1);
+        Err(self.error.clone())
+    }
+
+    fn retry_policy(&self) -> Option<RetryPolicy> {
+        Some(RetryPolicy::new(3, 100).with_retryable(|error| error.starts_with("Timeout")))
+    }
+}
+
+impl State<MachineTypes> for Done {
+    fn advance(&self) -> Result<Transition<MachineTypes>, String> {
+        Ok(Transition::Terminal)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    use crate::clock::ManualClock;
+    use crate::message_channel::create_channel;
+    use crate::state_machine::{MachineStatus, StateMachine, StepResult};
+    use crate::state_machine_orchestrator::{SimpleMachineOrchestrator, StateMachineOrchestrator};
+    use crate::tests::example_9_retries::{Calling, Done, MachineTypes};
+
+    fn orchestrator(clock: &ManualClock) -> SimpleMachineOrchestrator<MachineTypes> {
+        let mut orchestrator = SimpleMachineOrchestrator::<MachineTypes>::new(Box::new(|_| {}));
+        orchestrator.set_clock(Rc::new(clock.clone()));
+        orchestrator
+    }
+
+    #[test]
+    pub fn it_retries_with_backoff() {
+        let clock = ManualClock::new(0);
+        let mut orchestrator = orchestrator(&clock);
+        let (id, _) = orchestrator.create_machine(Box::new(Calling { failures: Rc::new(Cell::new(2)), error: "Timeout".to_string() })).unwrap();
+
+        assert_eq!(orchestrator.step_ready_machines()[0].1, Ok(StepResult::AwaitingRetry));
+        assert_eq!(orchestrator.get_state_machine(&id).unwrap().next_retry_at(), Some(100));
+
+        // Not due yet, even when every machine is stepped
+        clock.set(99);
+        assert!(orchestrator.step_ready_machines().is_empty());
+        orchestrator.step_all_machines();
+        assert_eq!(orchestrator.step_machine(&id), Ok(StepResult::AwaitingRetry));
+        assert_eq!(orchestrator.get_state_machine(&id).unwrap().attempts(), 1);
+
+        clock.set(100);
+        assert_eq!(orchestrator.step_ready_machines()[0].1, Ok(StepResult::AwaitingRetry));
+        assert_eq!(orchestrator.get_state_machine(&id).unwrap().next_retry_at(), Some(300));
+
+        clock.set(300);
+        assert_eq!(orchestrator.step_ready_machines()[0].1, Ok(StepResult::Running));
+        let machine = orchestrator.get_state_machine(&id).unwrap();
+        assert_eq!(machine.downcast_state::<Done>(), Some(&Done {}));
+        assert_eq!(machine.attempts(), 0);
+        assert_eq!(machine.next_retry_at(), None);
+    }
+
+    #[test]
+    pub fn it_fails_when_retries_run_out() {
+        let clock = ManualClock::new(0);
+        let mut orchestrator = orchestrator(&clock);
+        let (id, _) = orchestrator.create_machine(Box::new(Calling { failures: Rc::new(Cell::new(5)), error: "Timeout".to_string() })).unwrap();
+
+        for now in [0, 100] {
+            clock.set(now);
+            assert_eq!(orchestrator.step_ready_machines()[0].1, Ok(StepResult::AwaitingRetry));
+        }
+        clock.set(300);
+        assert!(orchestrator.step_ready_machines()[0].1.is_err());
+
+        let machine = orchestrator.get_state_machine(&id).unwrap();
+        assert_eq!(machine.status(), &MachineStatus::Failed { error: "Timeout".to_string() });
+        assert_eq!(machine.attempts(), 3);
+        assert_eq!(orchestrator.step_machine(&id), Ok(StepResult::Failed));
+    }
+
+    #[test]
+    pub fn it_replays_attempts_and_retry_times() {
+        let clock = ManualClock::new(0);
+        let (sender, _) = create_channel();
+        let (mut machine, _) = StateMachine::new("calling".to_string(), sender, Box::new(Calling { failures: Rc::new(Cell::new(5)), error: "Timeout".to_string() }));
+        machine.set_clock(Rc::new(clock.clone()));
+        machine.enable_journal();
+
+        assert_eq!(machine.step(), Ok(StepResult::AwaitingRetry));
+        clock.set(100);
+        assert_eq!(machine.step(), Ok(StepResult::AwaitingRetry));
+
+        let (sender, _) = create_channel();
+        let journal = machine.take_journal().unwrap();
+        let (replayed, _) = StateMachine::replay("calling".to_string(), sender, Box::new(Calling { failures: Rc::new(Cell::new(5)), error: "Timeout".to_string() }), journal).unwrap();
+        assert_eq!(replayed.attempts(), 2);
+        assert_eq!(replayed.next_retry_at(), Some(300));
+    }
+
+    #[test]
+    pub fn it_fails_immediately_on_errors_that_are_not_retryable() {
+        let clock = ManualClock::new(0);
+        let mut orchestrator = orchestrator(&clock);
+        let (id, _) = orchestrator.create_machine(Box::new(Calling { failures: Rc::new(Cell::new(1)), error: "Rejected".to_string() })).unwrap();
+
+        assert!(orchestrator.step_ready_machines()[0].1.is_err());
+
+        let machine = orchestrator.get_state_machine(&id).unwrap();
+        assert_eq!(machine.status(), &MachineStatus::Failed { error: "Rejected".to_string() });
+        assert_eq!(machine.next_retry_at(), None);
+    }
+}
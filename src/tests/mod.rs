@@ -1,3 +1,13 @@
-mod example_1_simple;
-mod example_2_simple_inbound_messages;
-mod example_3_simple_orchestrator;
\ No newline at end of file
+pub mod example_1_simple;
+pub mod example_2_simple_inbound_messages;
+mod example_3_simple_orchestrator;
+mod example_4_priority_messages;
+mod example_5_broadcast;
+pub mod example_6_machine_routing;
+mod example_7_idempotent_messages;
+pub mod example_8_cancellation;
+mod example_9_retries;
+mod example_10_machine_queries;
+mod example_11_admin_repairs;
+mod example_12_transition_tables;
+pub mod support;
@@ -0,0 +1,26 @@
+use std::cell::Cell;
+use std::rc::Rc;
+
+use crate::instructions::InstructionCounter;
+
+// Fakes shared by the tests of several modules.
+
+// Pretends every reading comes 100 instructions after the previous one.
+// Clones share the count, so a test can keep one to reset it.
+#[derive(Clone, Default)]
+pub struct SteadyInstructionCounter {
+    count: Rc<Cell<u64>>,
+}
+
+impl SteadyInstructionCounter {
+    pub fn reset(&self) {
+        self.count.set(0);
+    }
+}
+
+impl InstructionCounter for SteadyInstructionCounter {
+    fn instructions(&self) -> u64 {
+        self.count.set(self.count.get() + 100);
+        self.count.get()
+    }
+}
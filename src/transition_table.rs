@@ -0,0 +1,167 @@
+use std::fmt::Write;
+
+use crate::state::{short_type_name, State, StateType, Transition};
+
+type Guard<S> = Box<dyn Fn(&S) -> bool>;
+type TargetFactory<S, Types> = Box<dyn Fn(&S) -> Transition<Types>>;
+type Action<S, Types> = Box<dyn Fn(&S) -> Vec<<Types as StateType>::Out>>;
+
+/// A transition that is taken when its guard holds, emitting the messages produced by its actions.
+pub struct TransitionRule<S, Types: StateType> {
+    label: String,
+    // Name of the target state, None for terminal transitions
+    target: Option<String>,
+    guard: Guard<S>,
+    factory: TargetFactory<S, Types>,
+    actions: Vec<Action<S, Types>>,
+}
+
+impl<S: State<Types>, Types: StateType> TransitionRule<S, Types> {
+    /// Move to the state built by `target` when `guard` holds
+    pub fn next<T: State<Types>>(label: &str, guard: impl Fn(&S) -> bool + 'static, target: impl Fn(&S) -> T + 'static) -> Self {
+        TransitionRule {
+            label: label.to_string(),
+            target: Some(short_type_name::<T>()),
+            guard: Box::new(guard),
+            factory: Box::new(move |state| Transition::Next(Box::new(target(state)))),
+            actions: vec![],
+        }
+    }
+
+    /// Terminate the machine when `guard` holds
+    pub fn terminal(label: &str, guard: impl Fn(&S) -> bool + 'static) -> Self {
+        TransitionRule {
+            label: label.to_string(),
+            target: None,
+            guard: Box::new(guard),
+            factory: Box::new(|_| Transition::Terminal),
+            actions: vec![],
+        }
+    }
+
+    /// Emit the returned messages when the rule fires. Actions run in the order they were added.
+    pub fn with_action(mut self, action: impl Fn(&S) -> Vec<Types::Out> + 'static) -> Self {
+        self.actions.push(Box::new(action));
+        self
+    }
+
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Name of the target state, None for terminal transitions
+    pub fn target(&self) -> Option<&str> {
+        self.target.as_deref()
+    }
+
+    /// Whether the guard holds for the state
+    pub fn applies(&self, state: &S) -> bool {
+        (self.guard)(state)
+    }
+
+    /// The transition and the messages emitted by the actions, regardless of the guard
+    pub fn fire(&self, state: &S) -> (Transition<Types>, Vec<Types::Out>) {
+        let emitted = self.actions.iter().flat_map(|action| action(state)).collect();
+        ((self.factory)(state), emitted)
+    }
+}
+
+/// Ordered transition rules of a state. The first rule whose guard holds is taken.
+pub struct TransitionTable<S, Types: StateType> {
+    rules: Vec<TransitionRule<S, Types>>,
+}
+
+impl<S: State<Types>, Types: StateType> TransitionTable<S, Types> {
+    pub fn new() -> Self {
+        TransitionTable { rules: vec![] }
+    }
+
+    pub fn with_rule(mut self, rule: TransitionRule<S, Types>) -> Self {
+        self.rules.push(rule);
+        self
+    }
+
+    pub fn rules(&self) -> &[TransitionRule<S, Types>] {
+        &self.rules
+    }
+
+    /// Fire the first rule whose guard holds, if any
+    pub fn evaluate(&self, state: &S) -> Option<(Transition<Types>, Vec<Types::Out>)> {
+        self.rules.iter().find(|rule| rule.applies(state)).map(|rule| rule.fire(state))
+    }
+}
+
+impl<S: State<Types>, Types: StateType> Default for TransitionTable<S, Types> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A transition table with the state type erased, as returned by `State::transition_table`.
+pub trait StateTransitions<Types: StateType> {
+    /// Name of the state the table belongs to
+    fn state_name(&self) -> String;
+
+    /// Fire the first rule whose guard holds, if any. None when the state is not of the table's type.
+    fn fire(&self, state: &dyn State<Types>) -> Option<(Transition<Types>, Vec<Types::Out>)>;
+
+    /// Label and target state name of every rule, in order. Terminal rules have no target.
+    fn edges(&self) -> Vec<(String, Option<String>)>;
+}
+
+impl<S: State<Types>, Types: StateType> StateTransitions<Types> for TransitionTable<S, Types> {
+    fn state_name(&self) -> String {
+        short_type_name::<S>()
+    }
+
+    fn fire(&self, state: &dyn State<Types>) -> Option<(Transition<Types>, Vec<Types::Out>)> {
+        self.evaluate(state.as_any().downcast_ref::<S>()?)
+    }
+
+    fn edges(&self) -> Vec<(String, Option<String>)> {
+        self.rules.iter().map(|rule| (rule.label.clone(), rule.target.clone())).collect()
+    }
+}
+
+/// Render the tables as a Mermaid state diagram
+pub fn to_mermaid<Types: StateType>(tables: &[&dyn StateTransitions<Types>]) -> String {
+    let mut out = String::from("stateDiagram-v2\n");
+    for table in tables {
+        let from = table.state_name();
+        for (label, target) in table.edges() {
+            let target = target.unwrap_or_else(|| "[*]".to_string());
+            let _ = writeln!(out, "    {} --> {}: {}", from, target, label);
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use crate::state::Transition;
+    use crate::tests::example_2_simple_inbound_messages::{BlueMessageState, RedMessageState};
+    use crate::tests::example_6_machine_routing::{MachineTypes, Relay, RelayCommand};
+    use crate::transition_table::{TransitionRule, TransitionTable};
+
+    #[test]
+    pub fn it_evaluates_guards_in_isolation() {
+        let rule = TransitionRule::next("count >= 3", |state: &RedMessageState| state.count >= 3, |_| BlueMessageState { count: 0 });
+
+        assert!(!rule.applies(&RedMessageState { count: 2 }));
+        assert!(rule.applies(&RedMessageState { count: 3 }));
+        assert_eq!(rule.target(), Some("BlueMessageState"));
+    }
+
+    #[test]
+    pub fn it_emits_action_messages_when_a_rule_fires() {
+        let table = TransitionTable::<Relay, MachineTypes>::new()
+            .with_rule(TransitionRule::terminal("no next relay", |relay: &Relay| relay.next.is_none())
+                .with_action(|relay| vec![RelayCommand::Report { id: relay.id.clone() }]));
+        let relay = Relay { next: None, id: "last".to_string() };
+
+        let (transition, emitted) = table.evaluate(&relay).unwrap();
+        assert!(matches!(transition, Transition::Terminal));
+        assert_eq!(emitted, vec![RelayCommand::Report { id: "last".to_string() }]);
+        assert!(table.evaluate(&Relay { next: Some("1".to_string()), id: "first".to_string() }).is_none());
+    }
+}